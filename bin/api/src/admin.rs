@@ -0,0 +1,127 @@
+use crate::error::{ApiError, Problem, RequestContext};
+use crate::handlers::upstream_url;
+use crate::purge;
+use crate::state::AppState;
+use crate::store::StoreError;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::Method;
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::{error, info};
+use url::Url;
+
+/// Cache administration endpoints, guarded by a bearer token:
+///
+/// - `DELETE /_admin/cache?url=https://example.com/path` purges the entry for a `GET` of that public URL
+/// - `DELETE /_admin/cache/{service}` purges every entry of a service
+/// - `DELETE /_admin/cache/{service}/tags/{tag}` purges the entries tagged through `Surrogate-Key`
+/// - `GET /_admin/cache/stats` reports hits and misses per cache tier
+pub fn router(token: String) -> Router<AppState> {
+    Router::new()
+        .route("/_admin/cache", delete(purge_url))
+        .route("/_admin/cache/stats", get(stats))
+        .route("/_admin/cache/{service}", delete(purge_service))
+        .route("/_admin/cache/{service}/tags/{tag}", delete(purge_tag))
+        .layer(middleware::from_fn_with_state(Arc::<str>::from(token), require_token))
+}
+
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Result<Response, Problem> {
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // Compare digests so neither the comparison's timing nor its early exit on length reveals the token.
+    let provided = blake3::hash(provided.as_bytes());
+    let expected = blake3::hash(token.as_bytes());
+    if !bool::from(provided.as_bytes().ct_eq(expected.as_bytes())) {
+        return Err(RequestContext::from_headers(request.headers()).problem(ApiError::Unauthorized));
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize)]
+struct PurgeUrl {
+    url: String,
+}
+
+async fn purge_url(
+    State(state): State<AppState>,
+    ctx: RequestContext,
+    Query(query): Query<PurgeUrl>,
+) -> Result<Json<serde_json::Value>, Problem> {
+    let problem = |e: ApiError| ctx.problem(e);
+    let url = Url::parse(&query.url).map_err(|e| problem(ApiError::InvalidUrl(e.to_string())))?;
+    let domain = url
+        .domain()
+        .ok_or_else(|| problem(ApiError::InvalidUrl("No domain".into())))?;
+    let explicit_port = url.port();
+
+    let descriptor = state
+        .proxy_registry
+        .read()
+        .await
+        .find_proxy(domain, explicit_port, &Method::GET, url.path())
+        .map_err(|e| problem(e.into()))?
+        .clone();
+
+    // Same normalization as client requests: https without an explicit port, then the proxy's policy.
+    let mut public = url.clone();
+    let _ = public.set_scheme("https");
+    let _ = public.set_port(None);
+    let upstream = upstream_url(public, explicit_port, &descriptor.upstream).map_err(problem)?;
+
+    let purged = purge::purge_url(state.cache.as_ref(), &descriptor, &upstream)
+        .await
+        .map_err(|e| problem(store_error(e)))?;
+
+    info!("Purged {} ({} entries)", url, purged);
+    Ok(Json(serde_json::json!({ "purged": purged })))
+}
+
+async fn purge_service(
+    State(state): State<AppState>,
+    ctx: RequestContext,
+    Path(service): Path<String>,
+) -> Result<Json<serde_json::Value>, Problem> {
+    let purged = purge::purge_service(state.cache.as_ref(), &service)
+        .await
+        .map_err(|e| ctx.problem(store_error(e)))?;
+
+    info!("Purged service {} ({} entries)", service, purged);
+    Ok(Json(serde_json::json!({ "purged": purged })))
+}
+
+async fn purge_tag(
+    State(state): State<AppState>,
+    ctx: RequestContext,
+    Path((service, tag)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, Problem> {
+    let purged = purge::purge_tag(state.cache.as_ref(), &service, &tag)
+        .await
+        .map_err(|e| ctx.problem(store_error(e)))?;
+
+    info!("Purged tag {} of {} ({} entries)", tag, service, purged);
+    Ok(Json(serde_json::json!({ "purged": purged })))
+}
+
+async fn stats(State(state): State<AppState>) -> Json<serde_json::Value> {
+    match &state.tier_stats {
+        Some(stats) => Json(serde_json::json!(stats.as_ref())),
+        None => Json(serde_json::json!({})),
+    }
+}
+
+fn store_error(e: StoreError) -> ApiError {
+    error!("Purge failed: {}", e);
+    ApiError::InternalError(e.to_string())
+}
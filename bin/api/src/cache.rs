@@ -0,0 +1,275 @@
+use crate::entry_format::{self, EntryFormat};
+use crate::store::{CacheStore, Metadata};
+use axum::body::Bytes;
+use axum::http::header::{AGE, CONTENT_LENGTH, TRANSFER_ENCODING, WARNING};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use prtl_messages::ProxyDescriptor;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Prefix of the store keys of cache entries.
+pub const KEY_PREFIX: &str = "proxy:";
+
+/// Store key of a cache entry: `proxy:{service}:{hash}`.
+pub fn key(service_name: &str, hash: &str) -> String {
+    format!("{}{}:{}", KEY_PREFIX, service_name, hash)
+}
+
+/// How long entries of `desc` are kept after expiring, to be served stale.
+pub fn stale_window(desc: &ProxyDescriptor) -> Duration {
+    desc.stale_while_revalidate
+        .unwrap_or_default()
+        .max(desc.stale_if_error.unwrap_or_default())
+}
+
+/// A proxy response as shared between coalesced requests and stored in the cache.
+#[derive(Debug, Clone)]
+pub struct ProxiedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl IntoResponse for ProxiedResponse {
+    fn into_response(self) -> Response {
+        (self.status, self.headers, self.body).into_response()
+    }
+}
+
+impl From<http::Response<Vec<u8>>> for ProxiedResponse {
+    fn from(response: http::Response<Vec<u8>>) -> Self {
+        let (parts, body) = response.into_parts();
+        Self {
+            status: parts.status,
+            headers: parts.headers,
+            body: body.into(),
+        }
+    }
+}
+
+/// Why a cached response is served although it is no longer fresh.
+#[derive(Debug, Clone, Copy)]
+pub enum Staleness {
+    /// A background refresh is running.
+    Revalidating,
+    /// The proxy failed and the stale copy is the best we have.
+    RevalidationFailed,
+}
+
+/// The request a cache entry was fetched with, so it can be re-issued to refresh the entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRequest {
+    pub method: String,
+    pub uri: String,
+    /// Only the headers that select the entry's cache key are kept.
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Only kept when the body is part of the cache key.
+    #[serde(default)]
+    pub body: Vec<u8>,
+}
+
+impl StoredRequest {
+    pub fn new<B: AsRef<[u8]>>(request: &http::Request<B>) -> Self {
+        Self {
+            method: request.method().to_string(),
+            uri: request.uri().to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: request.body().as_ref().to_vec(),
+        }
+    }
+
+    pub fn retain_headers(&mut self, keep: impl Fn(&str) -> bool) {
+        self.headers.retain(|(name, _)| keep(name));
+    }
+
+    pub fn to_request(&self) -> Result<http::Request<Vec<u8>>, http::Error> {
+        let mut builder = http::Request::builder()
+            .method(self.method.as_str())
+            .uri(self.uri.as_str());
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_slice());
+        }
+        builder.body(self.body.clone())
+    }
+}
+
+/// A cached response together with the metadata needed to judge its freshness.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub status: u16,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    /// Unix time, in seconds, at which the response was received from the proxy.
+    pub stored_at: u64,
+    /// Seconds after `stored_at` during which the entry is fresh.
+    pub ttl: u64,
+    #[serde(default)]
+    pub request: Option<StoredRequest>,
+    /// `Surrogate-Key` tags of the response, kept for revalidations that don't repeat them.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl CacheEntry {
+    pub fn new(response: &ProxiedResponse, ttl: Duration, request: Option<StoredRequest>, tags: Vec<String>) -> Self {
+        Self {
+            status: response.status.as_u16(),
+            headers: response
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: response.body.to_vec(),
+            stored_at: unix_now(),
+            ttl: ttl.as_secs(),
+            request,
+            tags,
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.stored_at))
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.age() < Duration::from_secs(self.ttl)
+    }
+
+    /// Whether the entry expired at most `window` ago.
+    pub fn is_stale_within(&self, window: Option<Duration>) -> bool {
+        let Some(window) = window else {
+            return false;
+        };
+        self.age() < Duration::from_secs(self.ttl) + window
+    }
+
+    /// Builds the response for a client, with an `Age` header and, for stale entries, a `Warning`.
+    pub fn to_response(&self, staleness: Option<Staleness>) -> ProxiedResponse {
+        let mut headers = self.header_map();
+        headers.insert(AGE, HeaderValue::from(self.age().as_secs()));
+        match staleness {
+            Some(Staleness::Revalidating) => {
+                headers.insert(WARNING, HeaderValue::from_static("110 - \"Response is Stale\""));
+            }
+            Some(Staleness::RevalidationFailed) => {
+                headers.insert(WARNING, HeaderValue::from_static("111 - \"Revalidation Failed\""));
+            }
+            None => {}
+        }
+
+        ProxiedResponse {
+            status: StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK),
+            headers,
+            body: Bytes::from(self.body.clone()),
+        }
+    }
+
+    /// The stored response with the headers of a 304 that revalidated it applied on top (RFC 9111, section
+    /// 4.3.4).
+    pub fn revalidated(&self, not_modified: &HeaderMap) -> ProxiedResponse {
+        let mut headers = self.header_map();
+        for name in not_modified.keys() {
+            if name == CONTENT_LENGTH || name == TRANSFER_ENCODING {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+
+        ProxiedResponse {
+            status: StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK),
+            headers,
+            body: Bytes::from(self.body.clone()),
+        }
+    }
+
+    pub fn header_map(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(header_name), Ok(header_value)) =
+                (HeaderName::try_from(name.as_str()), HeaderValue::from_bytes(value))
+            {
+                headers.append(header_name, header_value);
+            }
+        }
+        headers
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Reads the entry at `key`, fetching its chunks if it was split. Unreadable entries count as misses.
+pub async fn get(store: &dyn CacheStore, key: &str) -> Option<CacheEntry> {
+    let read = |key: String| async move {
+        store.get(&key).await.unwrap_or_else(|e| {
+            tracing::debug!("Failed to read cache entry {}: {}", key, e);
+            None
+        })
+    };
+
+    let value = read(key.to_string()).await?;
+    let (header, inline) = match entry_format::parse(&value) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            tracing::debug!("Unreadable cache entry {}: {}", key, e);
+            return None;
+        }
+    };
+
+    let mut payload = inline.to_vec();
+    for chunk_key in header.chunk_keys(key) {
+        payload.extend(read(chunk_key).await?);
+    }
+    header
+        .decode(&payload)
+        .map_err(|e| tracing::debug!("Unreadable cache entry {}: {}", key, e))
+        .ok()
+}
+
+/// Stores `entry` until it has been stale for longer than `stale_window`, listed in `indexes` for as long.
+pub async fn put(
+    store: &dyn CacheStore,
+    format: &EntryFormat,
+    key: &str,
+    entry: &CacheEntry,
+    stale_window: Duration,
+    indexes: Vec<String>,
+) {
+    let expiry = Duration::from_secs(entry.ttl) + stale_window;
+    let encoded = match format.encode(key, entry) {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            tracing::warn!("Failed to encode cache entry {}: {}", key, e);
+            return;
+        }
+    };
+
+    // Chunks are listed in the entry's indexes too, so purges remove them with it.
+    let meta = Metadata { indexes };
+    for (chunk_key, chunk) in encoded.chunks {
+        if let Err(e) = store.put(&chunk_key, chunk, expiry, &meta).await {
+            tracing::warn!("Failed to store cache entry {}: {}", key, e);
+            return;
+        }
+    }
+    if let Err(e) = store.put(key, encoded.value, expiry, &meta).await {
+        tracing::warn!("Failed to store cache entry {}: {}", key, e);
+    }
+}
+
+/// Splits purged keys into entries and the other records that go with them, such as chunks.
+pub fn partition_entries(keys: Vec<String>) -> (Vec<String>, Vec<String>) {
+    keys.into_iter().partition(|key| key.starts_with(KEY_PREFIX))
+}
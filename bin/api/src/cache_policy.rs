@@ -0,0 +1,260 @@
+use crate::graphql;
+use crate::vary;
+use axum::http::header::{AGE, AUTHORIZATION, CACHE_CONTROL, DATE, EXPIRES};
+use axum::http::{HeaderMap, Method, StatusCode};
+use prtl_messages::{HashComponents, ProxyDescriptor};
+use std::time::{Duration, SystemTime};
+
+/// Decides whether a proxy response may be stored in the shared cache and for how long, following RFC 9111.
+///
+/// The descriptor's `cache_ttl` turns caching on and caps the lifetime derived from the response; proxies that
+/// set `ignore_cache_headers` get `cache_ttl` for every storable response instead.
+pub fn ttl(
+    desc: &ProxyDescriptor,
+    method: &Method,
+    request_headers: &HeaderMap,
+    status: StatusCode,
+    response_headers: &HeaderMap,
+) -> Option<Duration> {
+    let max_ttl = desc.cache_ttl?;
+
+    // A 304 only refreshes the response it validated, see `CacheEntry::revalidated`.
+    if status == StatusCode::NOT_MODIFIED {
+        return None;
+    }
+
+    if !is_stored_method(desc, method) {
+        return None;
+    }
+
+    let request_cc = CacheControl::parse(request_headers);
+    if request_cc.no_store {
+        return None;
+    }
+
+    if desc.ignore_cache_headers {
+        return is_heuristically_cacheable(status).then_some(max_ttl);
+    }
+
+    let response_cc = CacheControl::parse(response_headers);
+    if response_cc.no_store || response_cc.private || response_cc.no_cache {
+        return None;
+    }
+
+    // Responses to authenticated requests are only shared when the upstream says so explicitly.
+    if request_headers.contains_key(AUTHORIZATION)
+        && !(response_cc.public || response_cc.s_maxage.is_some() || response_cc.must_revalidate)
+    {
+        return None;
+    }
+
+    // `Vary: *` can't be captured by a cache key.
+    vary::fields(response_headers)?;
+
+    let lifetime = match explicit_lifetime(&response_cc, response_headers) {
+        Some(lifetime) => lifetime,
+        None if is_heuristically_cacheable(status) => max_ttl,
+        None => return None,
+    };
+
+    let age = header_secs(response_headers.get(AGE)).unwrap_or_default();
+    let ttl = lifetime.saturating_sub(age).min(max_ttl);
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+/// GET responses are stored, and POST responses when the key covers the method and body or, for GraphQL services,
+/// the operation. HEAD shares its cache key with GET, so storing its empty body would break GET responses.
+pub fn is_stored_method(desc: &ProxyDescriptor, method: &Method) -> bool {
+    match *method {
+        Method::GET => true,
+        Method::POST => {
+            graphql::is_graphql(desc)
+                || desc
+                    .hash_settings
+                    .contains(HashComponents::METHOD | HashComponents::BODY)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a body of `len` bytes is within the descriptor's `max_cached_body_size`.
+pub fn fits_body(desc: &ProxyDescriptor, len: usize) -> bool {
+    desc.max_cached_body_size.is_none_or(|max| len <= max)
+}
+
+/// Statuses that may be cached without explicit freshness information (RFC 9110, section 15.1). 206 is left
+/// out because the gateway doesn't combine partial content.
+fn is_heuristically_cacheable(status: StatusCode) -> bool {
+    matches!(
+        status.as_u16(),
+        200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+fn explicit_lifetime(cc: &CacheControl, headers: &HeaderMap) -> Option<Duration> {
+    if let Some(lifetime) = cc.s_maxage.or(cc.max_age) {
+        return Some(lifetime);
+    }
+
+    // An `Expires` that isn't a valid date, like `0`, means already expired (RFC 9111, section 5.3).
+    let expires = headers.get(EXPIRES)?;
+    let Some(expires) = httpdate(Some(expires)) else {
+        return Some(Duration::ZERO);
+    };
+    let date = httpdate(headers.get(DATE)).unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(date).unwrap_or_default())
+}
+
+fn header_secs(value: Option<&axum::http::HeaderValue>) -> Option<Duration> {
+    value?.to_str().ok()?.trim().parse().ok().map(Duration::from_secs)
+}
+
+fn httpdate(value: Option<&axum::http::HeaderValue>) -> Option<SystemTime> {
+    httpdate::parse_http_date(value?.to_str().ok()?).ok()
+}
+
+/// The `Cache-Control` directives the gateway acts on.
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<Duration>,
+    s_maxage: Option<Duration>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+
+        let directives = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let secs = || value.and_then(|v| v.parse().ok()).map(Duration::from_secs);
+
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                // Qualified forms like `private="set-cookie"` are treated as unqualified since the gateway
+                // doesn't strip the named fields.
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                "max-age" => cc.max_age = secs(),
+                "s-maxage" => cc.s_maxage = secs(),
+                _ => {}
+            }
+        }
+
+        cc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_TTL: Duration = Duration::from_secs(3600);
+
+    fn descriptor() -> ProxyDescriptor {
+        ProxyDescriptor {
+            service_name: "test".into(),
+            hash_settings: HashComponents::URL,
+            cache_ttl: Some(MAX_TTL),
+            ..Default::default()
+        }
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn response_ttl(response_headers: &[(&str, &str)]) -> Option<Duration> {
+        ttl(
+            &descriptor(),
+            &Method::GET,
+            &HeaderMap::new(),
+            StatusCode::OK,
+            &headers(response_headers),
+        )
+    }
+
+    #[test]
+    fn follows_max_age() {
+        assert_eq!(
+            response_ttl(&[("cache-control", "max-age=60")]),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            response_ttl(&[("cache-control", "max-age=60"), ("age", "20")]),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(response_ttl(&[("cache-control", "max-age=0")]), None);
+    }
+
+    #[test]
+    fn caps_lifetime_at_cache_ttl() {
+        assert_eq!(response_ttl(&[("cache-control", "max-age=86400")]), Some(MAX_TTL));
+        assert_eq!(response_ttl(&[]), Some(MAX_TTL));
+    }
+
+    #[test]
+    fn prefers_s_maxage() {
+        assert_eq!(
+            response_ttl(&[("cache-control", "max-age=60, s-maxage=120")]),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn honors_no_store() {
+        assert_eq!(response_ttl(&[("cache-control", "no-store, max-age=60")]), None);
+        assert_eq!(response_ttl(&[("cache-control", "private")]), None);
+
+        let request = headers(&[("cache-control", "no-store")]);
+        let ttl = ttl(&descriptor(), &Method::GET, &request, StatusCode::OK, &HeaderMap::new());
+        assert_eq!(ttl, None);
+    }
+
+    #[test]
+    fn follows_expires() {
+        let ttl = response_ttl(&[
+            ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("expires", "Sun, 06 Nov 1994 08:59:37 GMT"),
+        ]);
+        assert_eq!(ttl, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn invalid_expires_is_already_expired() {
+        assert_eq!(response_ttl(&[("expires", "0")]), None);
+        assert_eq!(
+            response_ttl(&[("expires", "0"), ("cache-control", "max-age=60")]),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn ignore_cache_headers_uses_cache_ttl() {
+        let desc = ProxyDescriptor {
+            ignore_cache_headers: true,
+            ..descriptor()
+        };
+        let response = headers(&[("cache-control", "no-store")]);
+        let ttl = ttl(&desc, &Method::GET, &HeaderMap::new(), StatusCode::OK, &response);
+        assert_eq!(ttl, Some(MAX_TTL));
+    }
+}
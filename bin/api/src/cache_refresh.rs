@@ -1,11 +1,29 @@
-use redis::AsyncCommands;
-use std::time::Duration;
-use tracing::{debug, error, info};
+use crate::cache::{self, CacheEntry};
+use crate::env;
+use crate::handlers::Fetch;
+use crate::popularity::HitTracker;
+use crate::state::AppState;
+use crate::store::{CacheStore, StoreError};
+use futures_util::stream::{self, StreamExt};
+use prtl_messages::ProxyDescriptor;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
 pub struct CacheRefreshConfig {
     pub refresh_interval_seconds: u64,
+    /// Fraction of an entry's TTL after which it is refreshed.
     pub refresh_threshold_ratio: f64,
-    pub max_refresh_per_scan: usize,
+    /// Per-service overrides of `refresh_threshold_ratio`.
+    pub service_ratios: HashMap<String, f64>,
+    /// Most popular keys considered per run.
+    pub top_n: usize,
+    /// Lookups within `hit_window` below which an entry is left to expire.
+    pub min_hits: u64,
+    /// Period over which lookups are counted.
+    pub hit_window: Duration,
+    /// Refreshes running at the same time.
+    pub max_concurrent_refreshes: usize,
 }
 
 impl Default for CacheRefreshConfig {
@@ -13,22 +31,73 @@ impl Default for CacheRefreshConfig {
         Self {
             refresh_interval_seconds: 60,
             refresh_threshold_ratio: 0.8,
-            max_refresh_per_scan: 10,
+            service_ratios: HashMap::new(),
+            top_n: 100,
+            min_hits: 2,
+            hit_window: Duration::from_secs(3600),
+            max_concurrent_refreshes: 4,
         }
     }
 }
 
+impl CacheRefreshConfig {
+    /// Reads the `CACHE_REFRESH_*` variables, keeping the defaults for missing or invalid ones.
+    /// `CACHE_REFRESH_SERVICE_RATIOS` is a comma separated list of `service=ratio` pairs.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let service_ratios = std::env::var("CACHE_REFRESH_SERVICE_RATIOS")
+            .map(|v| {
+                v.split(',')
+                    .filter_map(|pair| {
+                        let (service, ratio) = pair.split_once('=')?;
+                        Some((service.trim().to_string(), ratio.trim().parse().ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            refresh_interval_seconds: env::var("CACHE_REFRESH_INTERVAL_SECS")
+                .unwrap_or(defaults.refresh_interval_seconds),
+            refresh_threshold_ratio: env::var("CACHE_REFRESH_RATIO").unwrap_or(defaults.refresh_threshold_ratio),
+            service_ratios,
+            top_n: env::var("CACHE_REFRESH_TOP_N").unwrap_or(defaults.top_n),
+            min_hits: env::var("CACHE_REFRESH_MIN_HITS").unwrap_or(defaults.min_hits),
+            hit_window: env::var("CACHE_HIT_WINDOW_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.hit_window),
+            max_concurrent_refreshes: env::var("CACHE_REFRESH_CONCURRENCY")
+                .unwrap_or(defaults.max_concurrent_refreshes),
+        }
+    }
+
+    fn ratio(&self, service_name: &str) -> f64 {
+        self.service_ratios
+            .get(service_name)
+            .copied()
+            .unwrap_or(self.refresh_threshold_ratio)
+    }
+}
+
+/// Re-issues the requests behind cache entries that are close to expiry, so clients keep hitting fresh entries.
 pub struct CacheRefreshService {
-    redis: redis::aio::ConnectionManager,
+    state: AppState,
     config: CacheRefreshConfig,
 }
 
+/// A cache entry due for a refresh.
+struct Candidate {
+    key: String,
+    descriptor: ProxyDescriptor,
+    entry: CacheEntry,
+}
+
 impl CacheRefreshService {
-    pub fn new(redis: redis::aio::ConnectionManager, config: CacheRefreshConfig) -> Self {
-        Self { redis, config }
+    pub fn new(state: AppState, config: CacheRefreshConfig) -> Self {
+        Self { state, config }
     }
 
-    pub async fn run(mut self) {
+    pub async fn run(self) {
         info!(
             "Starting cache refresh service with interval {}s",
             self.config.refresh_interval_seconds
@@ -45,37 +114,218 @@ impl CacheRefreshService {
         }
     }
 
-    async fn refresh_old_cache_entries(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut redis = self.redis.clone();
+    async fn refresh_old_cache_entries(&self) -> Result<(), StoreError> {
+        let candidates = self.find_candidates().await?;
+        if candidates.is_empty() {
+            debug!("No cache entries to refresh");
+            return Ok(());
+        }
 
-        let keys: Vec<String> = redis.keys("proxy:*").await?;
+        let total = candidates.len();
+        let refreshed = stream::iter(candidates)
+            .map(|candidate| self.refresh(candidate))
+            .buffer_unordered(self.config.max_concurrent_refreshes.max(1))
+            .filter(|refreshed| std::future::ready(*refreshed))
+            .count()
+            .await;
 
-        if keys.is_empty() {
-            debug!("No cache entries found");
-            return Ok(());
+        info!("Refreshed {} of {} cache entries", refreshed, total);
+        Ok(())
+    }
+
+    /// Picks the most popular keys that are past their refresh threshold. Keys below `min_hits`
+    /// or not looked up within the hit window are left to expire.
+    async fn find_candidates(&self) -> Result<Vec<Candidate>, StoreError> {
+        let store = self.state.cache.as_ref();
+        let tracker = &self.state.hit_tracker;
+        tracker.prune(store).await?;
+
+        let hot_keys = tracker.hot_keys(store, self.config.min_hits, self.config.top_n).await?;
+        if hot_keys.is_empty() {
+            return Ok(Vec::new());
         }
 
-        debug!("Scanning {} cache entries", keys.len());
+        // One snapshot for the whole run rather than a registry lock per key.
+        let descriptors: HashMap<String, ProxyDescriptor> = self
+            .state
+            .proxy_registry
+            .read()
+            .await
+            .descriptors()
+            .map(|descriptor| (descriptor.service_name.clone(), descriptor.clone()))
+            .collect();
 
-        let mut refreshed_count = 0;
+        let mut candidates = Vec::new();
+        for (key, hits) in hot_keys {
+            // Keys are `proxy:{service}:{hash}`.
+            let Some(descriptor) = key
+                .strip_prefix(cache::KEY_PREFIX)
+                .and_then(|rest| rest.rsplit_once(':'))
+                .and_then(|(service_name, _)| descriptors.get(service_name))
+            else {
+                continue;
+            };
 
-        for key in keys.iter().take(self.config.max_refresh_per_scan) {
-            if refreshed_count >= self.config.max_refresh_per_scan {
-                break;
+            if descriptor.cache_ttl.is_none() {
+                continue;
             }
 
-            let ttl: i64 = redis.ttl(key.as_str()).await.unwrap_or(-1);
-
-            if ttl > 0 && ttl < (3600.0 * self.config.refresh_threshold_ratio) as i64 {
-                debug!("Cache entry {} has low TTL: {}s", key, ttl);
-                refreshed_count += 1;
+            let ratio = self.config.ratio(&descriptor.service_name);
+            if let Some(entry) = due_entry(store, tracker, &key, descriptor, ratio).await? {
+                debug!("Cache entry {} ({} hits) is due for a refresh", key, hits);
+                candidates.push(Candidate {
+                    key,
+                    descriptor: descriptor.clone(),
+                    entry,
+                });
             }
         }
 
-        if refreshed_count > 0 {
-            info!("Found {} cache entries to potentially refresh", refreshed_count);
+        Ok(candidates)
+    }
+
+    async fn refresh(&self, candidate: Candidate) -> bool {
+        let entry = candidate.entry;
+        let Some(stored_request) = &entry.request else {
+            debug!("Cache entry {} has no stored request, skipping", candidate.key);
+            return false;
+        };
+        let request = match stored_request.to_request() {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Invalid stored request for {}: {}", candidate.key, e);
+                return false;
+            }
+        };
+
+        let timeout = self
+            .state
+            .request_timeouts
+            .resolve(candidate.descriptor.request_timeout);
+        let primary = crate::hash::compute_cache_key(&request, &candidate.descriptor);
+        let fetch = Fetch::new(
+            &self.state,
+            &candidate.descriptor,
+            primary,
+            candidate.key,
+            Instant::now() + timeout,
+        );
+
+        match fetch.revalidate_shared(request, &entry).await {
+            Ok(response) if response.status.is_success() => true,
+            Ok(response) => {
+                warn!("Refresh of {} returned {}", fetch.cache_key, response.status);
+                false
+            }
+            Err(e) => {
+                warn!("Refresh of {} failed: {}", fetch.cache_key, e.code());
+                false
+            }
         }
+    }
+}
 
-        Ok(())
+/// The entry under `key` if it was looked up within the hit window and has less than `1 - ratio` of its TTL left.
+async fn due_entry(
+    store: &dyn CacheStore,
+    tracker: &HitTracker,
+    key: &str,
+    descriptor: &ProxyDescriptor,
+    ratio: f64,
+) -> Result<Option<CacheEntry>, StoreError> {
+    let idle = tracker.idle_time(store, key).await?;
+    if idle.is_none_or(|idle| idle > tracker.window()) {
+        return Ok(None);
+    }
+
+    let Some(ttl) = store.ttl(key).await.ok().flatten().filter(|ttl| !ttl.is_zero()) else {
+        return Ok(None);
+    };
+    let Some(entry) = cache::get(store, key).await else {
+        return Ok(None);
+    };
+
+    // The store keeps entries for the stale window on top of their TTL.
+    let fresh_left = ttl.as_secs() as i64 - cache::stale_window(descriptor).as_secs() as i64;
+    // Entries live for the TTL the upstream allowed, which may be well below the service's `cache_ttl`.
+    let threshold = entry.ttl as f64 * (1.0 - ratio);
+    if (fresh_left as f64) >= threshold {
+        return Ok(None);
+    }
+
+    debug!("Cache entry {} has {}s of freshness left", key, fresh_left);
+    Ok(Some(entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry_format::EntryFormat;
+    use crate::store::MemoryStore;
+
+    const KEY: &str = "proxy:svc:hash";
+
+    /// An entry fresh for 100s, stored without a stale window so its TTL in the store is what freshness it has
+    /// left, while `descriptor` declares a 30s one.
+    async fn stored() -> (MemoryStore, HitTracker, ProxyDescriptor) {
+        let store = MemoryStore::new(1024 * 1024);
+        let entry = CacheEntry {
+            status: 200,
+            headers: Vec::new(),
+            body: b"body".to_vec(),
+            stored_at: 1_700_000_000,
+            ttl: 100,
+            request: None,
+            tags: Vec::new(),
+        };
+        cache::put(&store, &EntryFormat::default(), KEY, &entry, Duration::ZERO, Vec::new()).await;
+
+        let descriptor = ProxyDescriptor {
+            service_name: "svc".into(),
+            stale_while_revalidate: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        (store, HitTracker::new(Duration::from_secs(3600)), descriptor)
+    }
+
+    #[tokio::test]
+    async fn refreshes_entries_past_the_threshold() {
+        let (store, tracker, descriptor) = stored().await;
+        tracker.record(KEY);
+        tracker.flush(&store).await;
+
+        // Without the stale window, about 70s of the 100s are left.
+        assert!(
+            due_entry(&store, &tracker, KEY, &descriptor, 0.2)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            due_entry(&store, &tracker, KEY, &descriptor, 0.4)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_entries_nobody_looked_up() {
+        let (store, tracker, descriptor) = stored().await;
+        assert!(
+            due_entry(&store, &tracker, KEY, &descriptor, 0.0)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        tracker.record("proxy:svc:missing");
+        tracker.flush(&store).await;
+        assert!(
+            due_entry(&store, &tracker, "proxy:svc:missing", &descriptor, 0.0)
+                .await
+                .unwrap()
+                .is_none()
+        );
     }
 }
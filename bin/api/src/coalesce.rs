@@ -0,0 +1,68 @@
+use crate::store::{CacheStore, StoreResult};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+/// Collapses concurrent calls sharing a key into one: the first caller runs its future, later callers wait for
+/// it and get a clone of the result.
+///
+/// If the running caller is cancelled, one of the waiters takes over with its own future, so a client going
+/// away doesn't fail the others.
+pub struct SingleFlight<T> {
+    calls: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub async fn run<F>(&self, key: &str, fut: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let cell = self.calls.lock().unwrap().entry(key.to_string()).or_default().clone();
+
+        let value = cell.get_or_init(|| fut).await.clone();
+
+        let mut calls = self.calls.lock().unwrap();
+        if calls.get(key).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+            calls.remove(key);
+        }
+
+        value
+    }
+}
+
+/// Lock in the cache store that lets one gateway out of several fetch a given response while the others wait for it to
+/// show up in the cache. Expires on its own so a crashed holder can't block a key for long.
+pub struct DistributedLock {
+    store: Arc<dyn CacheStore>,
+    key: String,
+    token: String,
+}
+
+impl DistributedLock {
+    pub async fn try_acquire(store: Arc<dyn CacheStore>, key: String, ttl: Duration) -> StoreResult<Option<Self>> {
+        let token = uuid::Uuid::new_v4().to_string();
+        let acquired = store.put_if_absent(&key, token.clone().into_bytes(), ttl).await?;
+        Ok(acquired.then_some(Self { store, key, token }))
+    }
+
+    pub async fn is_held(store: &dyn CacheStore, key: &str) -> StoreResult<bool> {
+        Ok(store.get(key).await?.is_some())
+    }
+
+    /// Deletes the lock unless it expired and was taken over by someone else in the meantime.
+    pub async fn release(self) {
+        if let Err(e) = self.store.delete_if_eq(&self.key, self.token.as_bytes()).await {
+            tracing::warn!("Failed to release lock {}: {}", self.key, e);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use crate::cache::ProxiedResponse;
+use axum::body::Bytes;
+use axum::http::header::{
+    AGE, CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, VARY,
+    WARNING,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use std::time::SystemTime;
+
+/// Headers a 304 carries over from the response it stands for (RFC 9110, section 15.4.5).
+const NOT_MODIFIED_HEADERS: [axum::http::HeaderName; 9] = [
+    CACHE_CONTROL,
+    CONTENT_LOCATION,
+    DATE,
+    ETAG,
+    EXPIRES,
+    LAST_MODIFIED,
+    VARY,
+    AGE,
+    WARNING,
+];
+
+/// The client's `If-None-Match` and `If-Modified-Since` preconditions, evaluated by the gateway against cached
+/// and freshly fetched responses instead of being forwarded to the proxy.
+#[derive(Debug, Default)]
+pub struct Preconditions {
+    if_none_match: Option<HeaderValue>,
+    if_modified_since: Option<SystemTime>,
+}
+
+impl Preconditions {
+    /// Removes the preconditions from `headers`, so the proxy always returns a full, cacheable response.
+    pub fn take(headers: &mut HeaderMap) -> Self {
+        let if_none_match = headers.remove(IF_NONE_MATCH);
+        let if_modified_since = headers.remove(IF_MODIFIED_SINCE);
+
+        Self {
+            if_modified_since: if_modified_since
+                .as_ref()
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| httpdate::parse_http_date(v).ok()),
+            if_none_match,
+        }
+    }
+
+    /// Turns `response` into a 304 when the client's copy is still current.
+    pub fn evaluate(&self, response: ProxiedResponse) -> ProxiedResponse {
+        if response.status != StatusCode::OK || !self.is_met(&response.headers) {
+            return response;
+        }
+
+        let mut headers = HeaderMap::new();
+        for name in NOT_MODIFIED_HEADERS {
+            for value in response.headers.get_all(&name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+
+        ProxiedResponse {
+            status: StatusCode::NOT_MODIFIED,
+            headers,
+            body: Bytes::new(),
+        }
+    }
+
+    /// `If-None-Match` takes precedence over `If-Modified-Since` (RFC 9110, section 13.2.2).
+    fn is_met(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            let Ok(tags) = if_none_match.to_str() else {
+                return false;
+            };
+            // `*` matches any current representation, with or without an entity tag.
+            if tags.trim() == "*" {
+                return true;
+            }
+            let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+                return false;
+            };
+            return tags.split(',').map(str::trim).any(|tag| weak_eq(tag, etag));
+        }
+
+        if let Some(since) = self.if_modified_since {
+            let last_modified = headers
+                .get(LAST_MODIFIED)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| httpdate::parse_http_date(v).ok());
+            return last_modified.is_some_and(|last_modified| last_modified <= since);
+        }
+
+        false
+    }
+}
+
+/// Weak comparison of two entity tags, ignoring the `W/` prefix.
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// Gives `response` an `ETag` derived from its body when the upstream didn't send a validator.
+pub fn ensure_validator(response: &mut ProxiedResponse) {
+    if response.headers.contains_key(ETAG) || response.headers.contains_key(LAST_MODIFIED) {
+        return;
+    }
+
+    let hash = blake3::hash(&response.body);
+    if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", &hash.to_hex()[..32])) {
+        response.headers.insert(ETAG, etag);
+    }
+}
+
+/// Adds the validators of a cached response to a request, so the proxy can answer 304 if nothing changed.
+pub fn add_validators(request_headers: &mut HeaderMap, cached_headers: &HeaderMap) {
+    if let Some(etag) = cached_headers.get(ETAG) {
+        request_headers.insert(IF_NONE_MATCH, etag.clone());
+    }
+    if let Some(last_modified) = cached_headers.get(LAST_MODIFIED) {
+        request_headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::CONTENT_TYPE;
+
+    const MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+    const LATER: &str = "Mon, 07 Nov 1994 08:49:37 GMT";
+
+    fn headers(pairs: &[(axum::http::HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn response(pairs: &[(axum::http::HeaderName, &str)]) -> ProxiedResponse {
+        ProxiedResponse {
+            status: StatusCode::OK,
+            headers: headers(pairs),
+            body: Bytes::from_static(b"hello"),
+        }
+    }
+
+    fn status(request: &[(axum::http::HeaderName, &str)], response_headers: &[(axum::http::HeaderName, &str)]) -> u16 {
+        let preconditions = Preconditions::take(&mut headers(request));
+        preconditions.evaluate(response(response_headers)).status.as_u16()
+    }
+
+    #[test]
+    fn take_removes_the_preconditions() {
+        let mut request = headers(&[
+            (IF_NONE_MATCH, "\"a\""),
+            (IF_MODIFIED_SINCE, MODIFIED),
+            (CONTENT_TYPE, "a/b"),
+        ]);
+        Preconditions::take(&mut request);
+        assert_eq!(request.len(), 1);
+    }
+
+    #[test]
+    fn matches_entity_tags_weakly() {
+        assert_eq!(status(&[(IF_NONE_MATCH, "\"a\"")], &[(ETAG, "\"a\"")]), 304);
+        assert_eq!(status(&[(IF_NONE_MATCH, "W/\"a\"")], &[(ETAG, "\"a\"")]), 304);
+        assert_eq!(status(&[(IF_NONE_MATCH, "\"b\", W/\"a\"")], &[(ETAG, "W/\"a\"")]), 304);
+        assert_eq!(status(&[(IF_NONE_MATCH, "\"b\"")], &[(ETAG, "\"a\"")]), 200);
+        assert_eq!(status(&[(IF_NONE_MATCH, "\"a\"")], &[]), 200);
+    }
+
+    #[test]
+    fn star_matches_any_response() {
+        assert_eq!(status(&[(IF_NONE_MATCH, "*")], &[(ETAG, "\"a\"")]), 304);
+        assert_eq!(status(&[(IF_NONE_MATCH, "*")], &[]), 304);
+    }
+
+    #[test]
+    fn compares_modification_dates() {
+        assert_eq!(
+            status(&[(IF_MODIFIED_SINCE, MODIFIED)], &[(LAST_MODIFIED, MODIFIED)]),
+            304
+        );
+        assert_eq!(status(&[(IF_MODIFIED_SINCE, LATER)], &[(LAST_MODIFIED, MODIFIED)]), 304);
+        assert_eq!(status(&[(IF_MODIFIED_SINCE, MODIFIED)], &[(LAST_MODIFIED, LATER)]), 200);
+        assert_eq!(
+            status(&[(IF_MODIFIED_SINCE, "yesterday")], &[(LAST_MODIFIED, MODIFIED)]),
+            200
+        );
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        // The dates alone would allow a 304, but the entity tag says the client's copy is outdated.
+        let request = [(IF_NONE_MATCH, "\"old\""), (IF_MODIFIED_SINCE, LATER)];
+        assert_eq!(status(&request, &[(ETAG, "\"new\""), (LAST_MODIFIED, MODIFIED)]), 200);
+    }
+
+    #[test]
+    fn only_turns_200s_into_304s() {
+        let preconditions = Preconditions::take(&mut headers(&[(IF_NONE_MATCH, "*")]));
+        let mut not_found = response(&[]);
+        not_found.status = StatusCode::NOT_FOUND;
+        assert_eq!(preconditions.evaluate(not_found).status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn not_modified_keeps_only_the_allowed_headers() {
+        let preconditions = Preconditions::take(&mut headers(&[(IF_NONE_MATCH, "\"a\"")]));
+        let evaluated = preconditions.evaluate(response(&[
+            (ETAG, "\"a\""),
+            (CACHE_CONTROL, "max-age=60"),
+            (VARY, "accept"),
+            (CONTENT_TYPE, "text/plain"),
+        ]));
+
+        assert_eq!(evaluated.status, StatusCode::NOT_MODIFIED);
+        assert!(evaluated.body.is_empty());
+        assert_eq!(
+            evaluated.headers,
+            headers(&[(ETAG, "\"a\""), (CACHE_CONTROL, "max-age=60"), (VARY, "accept")])
+        );
+    }
+
+    #[test]
+    fn derives_a_stable_etag_from_the_body() {
+        let mut first = response(&[]);
+        let mut second = response(&[]);
+        ensure_validator(&mut first);
+        ensure_validator(&mut second);
+        assert!(first.headers.contains_key(ETAG));
+        assert_eq!(first.headers[ETAG], second.headers[ETAG]);
+
+        let mut other = response(&[]);
+        other.body = Bytes::from_static(b"goodbye");
+        ensure_validator(&mut other);
+        assert_ne!(first.headers[ETAG], other.headers[ETAG]);
+    }
+
+    #[test]
+    fn keeps_upstream_validators() {
+        let mut tagged = response(&[(ETAG, "\"upstream\"")]);
+        ensure_validator(&mut tagged);
+        assert_eq!(tagged.headers[ETAG], "\"upstream\"");
+
+        let mut dated = response(&[(LAST_MODIFIED, MODIFIED)]);
+        ensure_validator(&mut dated);
+        assert!(!dated.headers.contains_key(ETAG));
+    }
+
+    #[test]
+    fn adds_cached_validators_to_refreshes() {
+        let mut request = HeaderMap::new();
+        add_validators(&mut request, &headers(&[(ETAG, "\"a\""), (LAST_MODIFIED, MODIFIED)]));
+        assert_eq!(request[IF_NONE_MATCH], "\"a\"");
+        assert_eq!(request[IF_MODIFIED_SINCE], MODIFIED);
+
+        let since = Preconditions::take(&mut request).if_modified_since.unwrap();
+        assert_eq!(httpdate::fmt_http_date(since), MODIFIED);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+/// Reverse-label trie over the domain patterns of all registered services.
+///
+/// A plain pattern such as `example.com` matches the domain itself and any subdomain, while `*.example.com`
+/// only matches subdomains. Lookups walk the labels of the host right to left, so they cost O(labels)
+/// regardless of how many services are registered.
+#[derive(Debug, Default)]
+pub struct DomainIndex {
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    /// Services matching a host that ends exactly at this node.
+    exact: Vec<String>,
+    /// Services matching any host strictly below this node.
+    subdomains: Vec<String>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.exact.is_empty() && self.subdomains.is_empty()
+    }
+}
+
+/// Normalizes a domain or pattern for indexing: lowercase and without a trailing dot.
+pub fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Checks that a pattern is either a plain domain or a single leading `*.` wildcard label followed by one.
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    let domain = pattern.strip_prefix("*.").unwrap_or(pattern);
+    !domain.is_empty() && domain.split('.').all(|label| !label.is_empty() && !label.contains('*'))
+}
+
+/// Whether two patterns claim the same hosts with the same specificity: identical patterns, or a plain domain and
+/// the wildcard over it, which both match its subdomains.
+pub fn patterns_overlap(a: &str, b: &str) -> bool {
+    let (a, b) = (normalize(a), normalize(b));
+    a.strip_prefix("*.").unwrap_or(&a) == b.strip_prefix("*.").unwrap_or(&b)
+}
+
+impl DomainIndex {
+    pub fn insert(&mut self, pattern: &str, service: &str) {
+        let pattern = normalize(pattern);
+        let (wildcard, domain) = match pattern.strip_prefix("*.") {
+            Some(domain) => (true, domain),
+            None => (false, pattern.as_str()),
+        };
+
+        let node = domain.rsplit('.').fold(&mut self.root, |node, label| {
+            node.children.entry(label.to_string()).or_default()
+        });
+
+        if !wildcard {
+            node.exact.push(service.to_string());
+        }
+        node.subdomains.push(service.to_string());
+    }
+
+    pub fn remove_service(&mut self, service: &str) {
+        fn prune(node: &mut Node, service: &str) {
+            node.exact.retain(|s| s != service);
+            node.subdomains.retain(|s| s != service);
+            for child in node.children.values_mut() {
+                prune(child, service);
+            }
+            node.children.retain(|_, child| !child.is_empty());
+        }
+
+        prune(&mut self.root, service);
+    }
+
+    /// Returns the services whose patterns match `host`, grouped from most to least specific: exact matches
+    /// first, then suffix matches ordered by the length of the matched suffix.
+    pub fn lookup(&self, host: &str) -> Vec<&[String]> {
+        let host = normalize(host);
+        let labels: Vec<&str> = host.rsplit('.').collect();
+
+        let mut suffixes = Vec::new();
+        let mut node = &self.root;
+        let mut exact = None;
+
+        for (depth, label) in labels.iter().enumerate() {
+            let Some(child) = node.children.get(*label) else {
+                break;
+            };
+            node = child;
+
+            if depth + 1 == labels.len() {
+                exact = Some(node.exact.as_slice());
+            } else {
+                suffixes.push(node.subdomains.as_slice());
+            }
+        }
+
+        exact
+            .into_iter()
+            .chain(suffixes.into_iter().rev())
+            .filter(|services| !services.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(patterns: &[(&str, &str)]) -> DomainIndex {
+        let mut index = DomainIndex::default();
+        for (pattern, service) in patterns {
+            index.insert(pattern, service);
+        }
+        index
+    }
+
+    fn lookup<'a>(index: &'a DomainIndex, host: &str) -> Vec<Vec<&'a str>> {
+        index
+            .lookup(host)
+            .into_iter()
+            .map(|services| services.iter().map(String::as_str).collect())
+            .collect()
+    }
+
+    #[test]
+    fn plain_pattern_matches_domain_and_subdomains() {
+        let index = index(&[("example.com", "a")]);
+        assert_eq!(lookup(&index, "example.com"), vec![vec!["a"]]);
+        assert_eq!(lookup(&index, "www.example.com"), vec![vec!["a"]]);
+        assert_eq!(lookup(&index, "deep.www.example.com"), vec![vec!["a"]]);
+        assert!(lookup(&index, "notexample.com").is_empty());
+    }
+
+    #[test]
+    fn wildcard_matches_only_subdomains() {
+        let index = index(&[("*.example.com", "a")]);
+        assert!(lookup(&index, "example.com").is_empty());
+        assert_eq!(lookup(&index, "www.example.com"), vec![vec!["a"]]);
+    }
+
+    #[test]
+    fn most_specific_match_comes_first() {
+        let index = index(&[
+            ("example.com", "a"),
+            ("*.api.example.com", "b"),
+            ("v1.api.example.com", "c"),
+        ]);
+        assert_eq!(
+            lookup(&index, "v1.api.example.com"),
+            vec![vec!["c"], vec!["b"], vec!["a"]]
+        );
+        assert_eq!(lookup(&index, "v2.api.example.com"), vec![vec!["b"], vec!["a"]]);
+        assert_eq!(lookup(&index, "api.example.com"), vec![vec!["a"]]);
+    }
+
+    #[test]
+    fn lookups_are_case_insensitive() {
+        let index = index(&[("Example.COM.", "a")]);
+        assert_eq!(lookup(&index, "WWW.example.com."), vec![vec!["a"]]);
+    }
+
+    #[test]
+    fn removed_services_no_longer_match() {
+        let mut index = index(&[("example.com", "a"), ("*.example.com", "b")]);
+        index.remove_service("a");
+        assert!(lookup(&index, "example.com").is_empty());
+        assert_eq!(lookup(&index, "www.example.com"), vec![vec!["b"]]);
+    }
+
+    #[test]
+    fn validates_patterns() {
+        assert!(is_valid_pattern("example.com"));
+        assert!(is_valid_pattern("*.example.com"));
+        assert!(!is_valid_pattern("*example.com"));
+        assert!(!is_valid_pattern("a.*.example.com"));
+        assert!(!is_valid_pattern("example..com"));
+        assert!(!is_valid_pattern("*."));
+    }
+
+    #[test]
+    fn plain_domain_overlaps_its_wildcard() {
+        assert!(patterns_overlap("example.com", "*.example.com"));
+        assert!(patterns_overlap("Example.com.", "example.com"));
+        assert!(!patterns_overlap("example.com", "*.api.example.com"));
+        assert!(!patterns_overlap("api.example.com", "*.example.com"));
+    }
+}
@@ -0,0 +1,340 @@
+//! How cache entries are laid out in the store.
+//!
+//! An entry is written as a versioned envelope: the `prtl` magic, a format version byte, the length of a
+//! MessagePack [`Header`] and the header itself, followed by the serialized [`CacheEntry`], compressed above
+//! [`EntryFormat::compression_threshold`]. Payloads over [`EntryFormat::chunk_size`] are split over chunk keys
+//! (see [`chunk_key`]) and the envelope only records how many there are. Values without the magic are not read:
+//! entries cached before the envelope existed live under keys the gateway no longer looks up.
+
+use crate::cache::{self, CacheEntry};
+use crate::env;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 4] = b"prtl";
+const VERSION: u8 = 1;
+
+/// Prefix of the store keys holding chunks of large entries.
+pub const CHUNK_PREFIX: &str = "chunk:";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    #[default]
+    Zstd,
+    Brotli,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "brotli" => Ok(Compression::Brotli),
+            other => Err(format!("Unknown cache compression: {}", other)),
+        }
+    }
+}
+
+/// Settings for writing entries. Reading follows whatever the envelope says.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryFormat {
+    pub compression: Compression,
+    /// Payloads smaller than this are stored uncompressed.
+    pub compression_threshold: usize,
+    /// Payloads larger than this, after compression, are split into chunks of this size.
+    pub chunk_size: usize,
+}
+
+impl Default for EntryFormat {
+    fn default() -> Self {
+        Self {
+            compression: Compression::default(),
+            compression_threshold: 1024,
+            chunk_size: 512 * 1024,
+        }
+    }
+}
+
+impl EntryFormat {
+    /// Reads `CACHE_COMPRESSION`, `CACHE_COMPRESSION_MIN_BYTES` and `CACHE_CHUNK_BYTES`, keeping the defaults for
+    /// missing or invalid ones.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            compression: env::var("CACHE_COMPRESSION").unwrap_or(defaults.compression),
+            compression_threshold: env::var("CACHE_COMPRESSION_MIN_BYTES").unwrap_or(defaults.compression_threshold),
+            chunk_size: env::var::<usize>("CACHE_CHUNK_BYTES")
+                .filter(|size| *size > 0)
+                .unwrap_or(defaults.chunk_size),
+        }
+    }
+
+    /// Serializes `entry` for storage under `key`. Chunks are to be stored before the envelope, so readers never
+    /// find an envelope whose chunks are missing.
+    pub fn encode(&self, key: &str, entry: &CacheEntry) -> Result<Encoded, FormatError> {
+        let raw = rmp_serde::to_vec(entry).map_err(|e| FormatError(e.to_string()))?;
+
+        let compression = match raw.len() < self.compression_threshold {
+            true => Compression::None,
+            false => self.compression,
+        };
+        let payload = compress(compression, &raw)?;
+
+        let (inline, chunks, chunk_id) = match payload.len() > self.chunk_size {
+            true => {
+                // Identifies this version of the entry, so a concurrent rewrite can't mix chunks.
+                let chunk_id = blake3::hash(&payload).to_hex()[..16].to_string();
+                let chunks = payload
+                    .chunks(self.chunk_size)
+                    .enumerate()
+                    .map(|(n, chunk)| (chunk_key(key, &chunk_id, n as u32), chunk.to_vec()))
+                    .collect();
+                (Vec::new(), chunks, chunk_id)
+            }
+            false => (payload, Vec::new(), String::new()),
+        };
+
+        let header = Header {
+            compression,
+            raw_len: raw.len() as u64,
+            chunks: chunks.len() as u32,
+            chunk_id,
+        };
+        let header = rmp_serde::to_vec(&header).map_err(|e| FormatError(e.to_string()))?;
+
+        let mut value = Vec::with_capacity(MAGIC.len() + 5 + header.len() + inline.len());
+        value.extend_from_slice(MAGIC);
+        value.push(VERSION);
+        value.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        value.extend_from_slice(&header);
+        value.extend_from_slice(&inline);
+
+        Ok(Encoded { value, chunks })
+    }
+}
+
+/// An entry ready to be stored.
+pub struct Encoded {
+    pub value: Vec<u8>,
+    /// Chunk keys and their contents, empty unless the payload was split.
+    pub chunks: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    compression: Compression,
+    /// Size of the payload before compression.
+    raw_len: u64,
+    /// Number of chunks the payload is split into; `0` when it follows the header.
+    chunks: u32,
+    chunk_id: String,
+}
+
+impl Header {
+    pub fn chunk_keys(&self, key: &str) -> Vec<String> {
+        (0..self.chunks).map(|n| chunk_key(key, &self.chunk_id, n)).collect()
+    }
+
+    pub fn decode(&self, payload: &[u8]) -> Result<CacheEntry, FormatError> {
+        let raw = decompress(self.compression, payload, self.raw_len)?;
+        rmp_serde::from_slice(&raw).map_err(|e| FormatError(e.to_string()))
+    }
+}
+
+/// Splits a stored envelope into its header and the payload that follows it, empty when the payload is chunked.
+pub fn parse(value: &[u8]) -> Result<(Header, &[u8]), FormatError> {
+    let rest = value
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| FormatError("Not a cache entry envelope".into()))?;
+
+    let invalid = || FormatError("Truncated cache entry".into());
+    let (&version, rest) = rest.split_first().ok_or_else(invalid)?;
+    if version != VERSION {
+        return Err(FormatError(format!("Unknown cache entry version {}", version)));
+    }
+    let (len, rest) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+    let len = u32::from_le_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(invalid());
+    }
+    let (header, inline) = rest.split_at(len);
+
+    let header = rmp_serde::from_slice(header).map_err(|e| FormatError(e.to_string()))?;
+    Ok((header, inline))
+}
+
+/// Store key of chunk `n` of the entry at `key`: `chunk:{key}:{chunk_id}:{n}`. Chunk keys live under their own
+/// prefix so they never count as entries and stay out of the in-process cache tier.
+pub fn chunk_key(key: &str, chunk_id: &str, n: u32) -> String {
+    format!("{}{}:{}:{}", CHUNK_PREFIX, key, chunk_id, n)
+}
+
+/// Prefix of the chunk keys of every entry of a service.
+pub fn service_chunk_prefix(service_name: &str) -> String {
+    format!("{}{}", CHUNK_PREFIX, cache::key(service_name, ""))
+}
+
+fn compress(compression: Compression, raw: &[u8]) -> Result<Vec<u8>, FormatError> {
+    let io_error = |e: std::io::Error| FormatError(e.to_string());
+    match compression {
+        Compression::None => Ok(raw.to_vec()),
+        Compression::Zstd => zstd::bulk::compress(raw, 3).map_err(io_error),
+        Compression::Brotli => {
+            let mut out = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                writer.write_all(raw).map_err(io_error)?;
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// Decompresses `payload`, refusing to produce more than the `raw_len` bytes recorded in the header.
+fn decompress(compression: Compression, payload: &[u8], raw_len: u64) -> Result<Vec<u8>, FormatError> {
+    let io_error = |e: std::io::Error| FormatError(e.to_string());
+    let raw = match compression {
+        Compression::None => payload.to_vec(),
+        Compression::Zstd => zstd::bulk::decompress(payload, raw_len as usize).map_err(io_error)?,
+        Compression::Brotli => {
+            let mut raw = Vec::new();
+            brotli::Decompressor::new(payload, 4096)
+                .take(raw_len + 1)
+                .read_to_end(&mut raw)
+                .map_err(io_error)?;
+            raw
+        }
+    };
+
+    if raw.len() as u64 != raw_len {
+        return Err(FormatError("Cache entry size mismatch".into()));
+    }
+    Ok(raw)
+}
+
+#[derive(Debug)]
+pub struct FormatError(String);
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{CacheStore, MemoryStore};
+    use std::time::Duration;
+
+    const KEY: &str = "proxy:svc:hash";
+
+    fn entry(body_len: usize) -> CacheEntry {
+        CacheEntry {
+            status: 200,
+            headers: vec![("content-type".into(), b"text/plain".to_vec())],
+            body: (0..body_len).map(|i| (i % 251) as u8).collect(),
+            stored_at: 1_700_000_000,
+            ttl: 60,
+            request: None,
+            tags: vec!["news".into()],
+        }
+    }
+
+    fn assert_same(a: &CacheEntry, b: &CacheEntry) {
+        assert_eq!(
+            (a.status, &a.headers, &a.body, a.stored_at, a.ttl, &a.tags),
+            (b.status, &b.headers, &b.body, b.stored_at, b.ttl, &b.tags)
+        );
+    }
+
+    fn decode_inline(value: &[u8]) -> CacheEntry {
+        let (header, inline) = parse(value).unwrap();
+        header.decode(inline).unwrap()
+    }
+
+    #[test]
+    fn roundtrips_with_every_compression() {
+        let original = entry(64 * 1024);
+        for compression in [Compression::None, Compression::Zstd, Compression::Brotli] {
+            let format = EntryFormat {
+                compression,
+                ..EntryFormat::default()
+            };
+            let encoded = format.encode(KEY, &original).unwrap();
+            assert!(encoded.chunks.is_empty());
+            assert!(encoded.value.starts_with(MAGIC));
+            assert_same(&decode_inline(&encoded.value), &original);
+        }
+    }
+
+    #[test]
+    fn leaves_small_payloads_uncompressed() {
+        let encoded = EntryFormat::default().encode(KEY, &entry(16)).unwrap();
+        let (header, _) = parse(&encoded.value).unwrap();
+        assert_eq!(header.compression, Compression::None);
+    }
+
+    #[test]
+    fn rejects_values_without_the_envelope() {
+        let bare = rmp_serde::to_vec(&entry(100)).unwrap();
+        assert!(parse(&bare).is_err());
+    }
+
+    #[test]
+    fn rejects_damaged_envelopes() {
+        let mut value = EntryFormat::default().encode(KEY, &entry(4096)).unwrap().value;
+        assert!(parse(&value[..MAGIC.len() + 3]).is_err());
+
+        value[MAGIC.len()] = VERSION + 1;
+        assert!(parse(&value).is_err());
+    }
+
+    #[test]
+    fn splits_large_payloads_into_chunks() {
+        let format = EntryFormat {
+            compression: Compression::None,
+            chunk_size: 1000,
+            ..EntryFormat::default()
+        };
+        let original = entry(4500);
+        let encoded = format.encode(KEY, &original).unwrap();
+        assert!(encoded.chunks.len() >= 5);
+        assert!(encoded.chunks.iter().all(|(key, _)| key.starts_with(CHUNK_PREFIX)));
+
+        let (header, inline) = parse(&encoded.value).unwrap();
+        assert!(inline.is_empty());
+        let keys: Vec<String> = encoded.chunks.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(header.chunk_keys(KEY), keys);
+
+        let payload: Vec<u8> = encoded.chunks.into_iter().flat_map(|(_, chunk)| chunk).collect();
+        assert_same(&header.decode(&payload).unwrap(), &original);
+    }
+
+    #[tokio::test]
+    async fn reassembles_chunked_entries_from_the_store() {
+        let store = MemoryStore::new(16 * 1024 * 1024);
+        let format = EntryFormat {
+            chunk_size: 4096,
+            ..EntryFormat::default()
+        };
+        // Hash output doesn't compress, so the payload still needs several chunks.
+        let mut original = entry(0);
+        original.body = vec![0; 64 * 1024];
+        blake3::Hasher::new().finalize_xof().fill(&mut original.body);
+
+        cache::put(&store, &format, KEY, &original, Duration::ZERO, Vec::new()).await;
+        assert!(store.scan_prefix(CHUNK_PREFIX).await.unwrap().len() > 1);
+        assert_same(&cache::get(&store, KEY).await.unwrap(), &original);
+
+        // An entry whose chunks are gone is a miss, not garbage.
+        let chunks = store.scan_prefix(CHUNK_PREFIX).await.unwrap();
+        store.delete(&chunks[..1]).await.unwrap();
+        assert!(cache::get(&store, KEY).await.is_none());
+    }
+}
@@ -0,0 +1,6 @@
+use std::str::FromStr;
+
+/// Parses the environment variable `name`, or `None` when it is unset or invalid so callers keep their default.
+pub fn var<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
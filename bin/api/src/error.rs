@@ -1,26 +1,381 @@
-use axum::http::StatusCode;
+use crate::registry::RouteError;
+use axum::extract::FromRequestParts;
+use axum::http::header::{ALLOW, CONTENT_TYPE, RETRY_AFTER};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
 use axum::response::{IntoResponse, Response};
+use prtl_messages::{ProxyError, ProxyErrorKind};
+use std::convert::Infallible;
+use std::time::Duration;
 
-#[derive(Debug)]
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, Clone)]
 pub enum ApiError {
     InvalidPath,
     InvalidUrl(String),
     NoParserAvailable,
+    PortNotAllowed(u16),
+    InvalidTimeout(String),
+    /// Missing or wrong admin token.
+    Unauthorized,
+    RouteNotFound,
+    MethodNotAllowed(Vec<Method>),
+    /// The GraphQL operation is not in the service's persisted query allowlist.
+    OperationNotAllowed,
+    PayloadTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// The proxy is registered but did not answer before the deadline.
+    ProxyTimeout,
+    /// No proxy instance is listening, or the bus could not deliver the request.
+    ProxyUnreachable,
+    /// The proxy answered with something that is not a valid bus message.
+    ProtocolError,
+    /// The proxy rejected the request; the message comes from the proxy and is shown to the client.
+    ProxyBadRequest(String),
+    /// The proxy could not get a usable answer from its upstream.
+    UpstreamError,
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// Gateway-side failure. The message is logged, never sent to clients.
     InternalError(String),
 }
 
-impl IntoResponse for ApiError {
+impl ApiError {
+    /// Stable, machine-readable identifier of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidPath => "invalid_path",
+            ApiError::InvalidUrl(_) => "invalid_url",
+            ApiError::NoParserAvailable => "no_proxy_available",
+            ApiError::PortNotAllowed(_) => "port_not_allowed",
+            ApiError::InvalidTimeout(_) => "invalid_timeout",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::RouteNotFound => "route_not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::OperationNotAllowed => "operation_not_allowed",
+            ApiError::PayloadTooLarge { .. } => "payload_too_large",
+            ApiError::ProxyTimeout => "proxy_timeout",
+            ApiError::ProxyUnreachable => "proxy_unreachable",
+            ApiError::ProtocolError => "protocol_error",
+            ApiError::ProxyBadRequest(_) => "bad_request",
+            ApiError::UpstreamError => "upstream_error",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::InternalError(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidPath
+            | ApiError::InvalidUrl(_)
+            | ApiError::PortNotAllowed(_)
+            | ApiError::InvalidTimeout(_)
+            | ApiError::ProxyBadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::NoParserAvailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::RouteNotFound => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::OperationNotAllowed => StatusCode::FORBIDDEN,
+            ApiError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::ProxyTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::ProxyUnreachable | ApiError::ProtocolError | ApiError::UpstreamError => StatusCode::BAD_GATEWAY,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether repeating the same request may succeed.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ApiError::NoParserAvailable
+                | ApiError::ProxyTimeout
+                | ApiError::ProxyUnreachable
+                | ApiError::UpstreamError
+                | ApiError::RateLimited { .. }
+        )
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::InvalidPath => "Invalid path",
+            ApiError::InvalidUrl(_) => "Invalid URL",
+            ApiError::NoParserAvailable => "No proxy available for this domain",
+            ApiError::PortNotAllowed(_) => "Port not allowed",
+            ApiError::InvalidTimeout(_) => "Invalid timeout",
+            ApiError::Unauthorized => "Unauthorized",
+            ApiError::RouteNotFound => "No proxy route matches this path",
+            ApiError::MethodNotAllowed(_) => "Method not allowed for this path",
+            ApiError::OperationNotAllowed => "GraphQL operation not allowed",
+            ApiError::PayloadTooLarge { .. } => "Payload too large",
+            ApiError::ProxyTimeout => "Proxy timed out",
+            ApiError::ProxyUnreachable => "Proxy unreachable",
+            ApiError::ProtocolError => "Invalid proxy response",
+            ApiError::ProxyBadRequest(_) => "Request rejected by proxy",
+            ApiError::UpstreamError => "Upstream request failed",
+            ApiError::RateLimited { .. } => "Rate limited",
+            ApiError::InternalError(_) => "Internal error",
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        match self {
+            ApiError::InvalidUrl(msg) | ApiError::InvalidTimeout(msg) | ApiError::ProxyBadRequest(msg) => {
+                Some(msg.clone())
+            }
+            ApiError::PortNotAllowed(port) => Some(format!("Port {} is not allowed for this domain", port)),
+            ApiError::PayloadTooLarge { size, limit } => Some(format!(
+                "Request of {} bytes exceeds the limit of {} bytes",
+                size, limit
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProxyError> for ApiError {
+    fn from(error: ProxyError) -> Self {
+        match error.kind {
+            ProxyErrorKind::BadRequest => ApiError::ProxyBadRequest(error.message),
+            ProxyErrorKind::UpstreamFailure => ApiError::UpstreamError,
+            ProxyErrorKind::RateLimited => ApiError::RateLimited {
+                retry_after: error.retry_after,
+            },
+            ProxyErrorKind::Internal => ApiError::InternalError(format!("Proxy error: {}", error.message)),
+            ProxyErrorKind::UnsupportedMessage => ApiError::ProtocolError,
+            ProxyErrorKind::DeadlineExceeded => ApiError::ProxyTimeout,
+        }
+    }
+}
+
+impl From<RouteError> for ApiError {
+    fn from(error: RouteError) -> Self {
+        match error {
+            RouteError::NoProxy => ApiError::NoParserAvailable,
+            RouteError::PortNotAllowed(port) => ApiError::PortNotAllowed(port),
+            RouteError::NoRoute => ApiError::RouteNotFound,
+            RouteError::MethodNotAllowed(allowed) => ApiError::MethodNotAllowed(allowed),
+        }
+    }
+}
+
+/// Context attached to error responses so clients can correlate them with gateway logs.
+#[derive(Debug)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub service: Option<String>,
+}
+
+impl RequestContext {
+    /// Uses the client's `x-request-id` if present, otherwise generates one.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let request_id = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        Self {
+            request_id,
+            service: None,
+        }
+    }
+
+    pub fn problem(&self, error: ApiError) -> Problem {
+        Problem {
+            error,
+            request_id: self.request_id.clone(),
+            service: self.service.clone(),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_headers(&parts.headers))
+    }
+}
+
+/// An [`ApiError`] rendered as an RFC 7807 `application/problem+json` response.
+#[derive(Debug)]
+pub struct Problem {
+    error: ApiError,
+    request_id: String,
+    service: Option<String>,
+}
+
+impl IntoResponse for Problem {
     fn into_response(self) -> Response {
-        let (status, message) = match self {
-            ApiError::InvalidPath => (StatusCode::BAD_REQUEST, "Invalid path".to_string()),
-            ApiError::InvalidUrl(msg) => (StatusCode::BAD_REQUEST, format!("Invalid URL: {}", msg)),
-            ApiError::NoParserAvailable => (
-                StatusCode::SERVICE_UNAVAILABLE,
-                "No proxy available for this domain".to_string(),
-            ),
-            ApiError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Internal error: {}", msg)),
-        };
-
-        (status, message).into_response()
+        let error = self.error;
+        let status = error.status();
+
+        if let ApiError::InternalError(msg) = &error {
+            tracing::error!("Internal error (request {}): {}", self.request_id, msg);
+        }
+
+        let mut body = serde_json::json!({
+            "type": format!("urn:prtl:error:{}", error.code()),
+            "title": error.title(),
+            "status": status.as_u16(),
+            "code": error.code(),
+            "retryable": error.retryable(),
+        });
+        if let Some(detail) = error.detail() {
+            body["detail"] = detail.into();
+        }
+        body["request_id"] = self.request_id.as_str().into();
+        if let Some(service) = self.service {
+            body["service"] = service.into();
+        }
+
+        let mut response = (status, body.to_string()).into_response();
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+
+        if let Ok(value) = HeaderValue::from_str(&self.request_id) {
+            headers.insert(REQUEST_ID_HEADER, value);
+        }
+
+        if let ApiError::MethodNotAllowed(allowed) = &error {
+            let mut allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+            allow.sort_unstable();
+            allow.dedup();
+            if let Ok(value) = HeaderValue::from_str(&allow.join(", ")) {
+                headers.insert(ALLOW, value);
+            }
+        }
+
+        if let ApiError::RateLimited {
+            retry_after: Some(retry_after),
+        } = &error
+        {
+            // Round up so clients never retry before the proxy is ready.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(service: Option<&str>) -> RequestContext {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("req-1"));
+        RequestContext {
+            service: service.map(str::to_string),
+            ..RequestContext::from_headers(&headers)
+        }
+    }
+
+    async fn render(error: ApiError) -> (Response, serde_json::Value) {
+        let mut response = context(Some("news")).problem(error).into_response();
+        let body = axum::body::to_bytes(std::mem::take(response.body_mut()), usize::MAX)
+            .await
+            .unwrap();
+        (response, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn keeps_or_generates_request_ids() {
+        assert_eq!(context(None).request_id, "req-1");
+
+        let generated = RequestContext::from_headers(&HeaderMap::new()).request_id;
+        assert!(!generated.is_empty());
+        assert_ne!(generated, RequestContext::from_headers(&HeaderMap::new()).request_id);
+    }
+
+    #[tokio::test]
+    async fn renders_problem_json() {
+        let (response, body) = render(ApiError::PortNotAllowed(8080)).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "req-1");
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "type": "urn:prtl:error:port_not_allowed",
+                "title": "Port not allowed",
+                "status": 400,
+                "code": "port_not_allowed",
+                "retryable": false,
+                "detail": "Port 8080 is not allowed for this domain",
+                "request_id": "req-1",
+                "service": "news",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn never_shows_internal_messages() {
+        let (response, body) = render(ApiError::InternalError("redis is down".into())).await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["code"], "internal_error");
+        assert!(body.get("detail").is_none());
+    }
+
+    #[tokio::test]
+    async fn lists_allowed_methods() {
+        let (response, _) = render(ApiError::MethodNotAllowed(vec![
+            Method::POST,
+            Method::GET,
+            Method::POST,
+        ]))
+        .await;
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, POST");
+    }
+
+    #[tokio::test]
+    async fn rounds_retry_after_up() {
+        let (response, body) = render(ApiError::RateLimited {
+            retry_after: Some(Duration::from_millis(1500)),
+        })
+        .await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+        assert_eq!(body["retryable"], true);
+
+        let (response, _) = render(ApiError::RateLimited { retry_after: None }).await;
+        assert!(response.headers().get(RETRY_AFTER).is_none());
+    }
+
+    #[test]
+    fn maps_proxy_errors() {
+        let mapped: Vec<&str> = [
+            ProxyErrorKind::BadRequest,
+            ProxyErrorKind::UpstreamFailure,
+            ProxyErrorKind::RateLimited,
+            ProxyErrorKind::Internal,
+            ProxyErrorKind::UnsupportedMessage,
+            ProxyErrorKind::DeadlineExceeded,
+        ]
+        .into_iter()
+        .map(|kind| ApiError::from(ProxyError::new(kind, "upstream said no")).code())
+        .collect();
+        assert_eq!(
+            mapped,
+            [
+                "bad_request",
+                "upstream_error",
+                "rate_limited",
+                "internal_error",
+                "protocol_error",
+                "proxy_timeout"
+            ]
+        );
     }
 }
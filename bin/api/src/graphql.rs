@@ -0,0 +1,298 @@
+use crate::error::ApiError;
+use crate::hash;
+use axum::http::Method;
+use graphql_parser::query::{Definition, OperationDefinition, parse_query};
+use http::Request;
+use prtl_messages::{ParserCapabilities, ProxyDescriptor};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+pub fn is_graphql(desc: &ProxyDescriptor) -> bool {
+    desc.capabilities.contains(&ParserCapabilities::GraphQl)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+/// A GraphQL operation as sent in a GET query string or a POST JSON body.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    /// `None` when the kind can't be told, e.g. for an ambiguous document.
+    pub kind: Option<OperationKind>,
+    pub name: Option<String>,
+    /// The document reprinted by the parser, without comments and with uniform formatting.
+    pub normalized_query: Option<String>,
+    /// Variables serialized with sorted keys.
+    pub variables: Vec<u8>,
+    /// SHA-256 of the document, given by the client as an automatic persisted query or computed from the query.
+    pub query_hash: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawOperation {
+    query: Option<String>,
+    operation_name: Option<String>,
+    #[serde(default)]
+    variables: Value,
+    #[serde(default)]
+    extensions: Value,
+}
+
+impl Operation {
+    /// Parses the operation of a GET or POST request. Returns `None` for batches and requests that aren't
+    /// GraphQL at all, which are proxied but never cached.
+    pub fn from_request<B: AsRef<[u8]>>(request: &Request<B>) -> Option<Self> {
+        Self::parse(request.method(), request.uri().query(), request.body().as_ref())
+    }
+
+    /// Parses an operation from the parts of a request, see [`Operation::from_request`].
+    pub fn parse(method: &Method, query: Option<&str>, body: &[u8]) -> Option<Self> {
+        let raw = match *method {
+            Method::GET | Method::HEAD => {
+                let mut raw = RawOperation::default();
+                for (name, value) in url::form_urlencoded::parse(query?.as_bytes()) {
+                    match name.as_ref() {
+                        "query" => raw.query = Some(value.into_owned()),
+                        "operationName" => raw.operation_name = Some(value.into_owned()),
+                        "variables" => raw.variables = serde_json::from_str(&value).ok()?,
+                        "extensions" => raw.extensions = serde_json::from_str(&value).ok()?,
+                        _ => {}
+                    }
+                }
+                raw
+            }
+            Method::POST => serde_json::from_slice(body).ok()?,
+            _ => return None,
+        };
+
+        let persisted_hash = raw.extensions["persistedQuery"]["sha256Hash"]
+            .as_str()
+            .map(str::to_ascii_lowercase);
+        let name = raw.operation_name.filter(|name| !name.is_empty());
+
+        let (kind, normalized_query, query_hash) = match &raw.query {
+            Some(query) => {
+                let computed = format!("{:x}", Sha256::digest(query.as_bytes()));
+                // A persisted hash that doesn't match the document is a client error the upstream reports.
+                if persisted_hash.as_ref().is_some_and(|hash| *hash != computed) {
+                    return None;
+                }
+                let (kind, normalized) = parse_document(query, name.as_deref())?;
+                (kind, Some(normalized), Some(computed))
+            }
+            // Automatic persisted queries send only the hash. Clients use GET for queries only, so hash-only GETs
+            // are treated as queries.
+            None => {
+                let kind = (*method != Method::POST).then_some(OperationKind::Query);
+                (kind, None, Some(persisted_hash?))
+            }
+        };
+
+        let mut variables = Vec::new();
+        if !raw.variables.is_null() {
+            hash::write_canonical_json(&raw.variables, &mut variables);
+        }
+
+        Some(Self {
+            kind,
+            name,
+            normalized_query,
+            variables,
+            query_hash,
+        })
+    }
+
+    pub fn is_query(&self) -> bool {
+        self.kind == Some(OperationKind::Query)
+    }
+}
+
+/// Whether the response to `request` may be stored. Only GraphQL queries are, other services leave it to
+/// [`crate::cache_policy`].
+pub fn is_cacheable<B: AsRef<[u8]>>(desc: &ProxyDescriptor, request: &Request<B>) -> bool {
+    !is_graphql(desc) || Operation::from_request(request).is_some_and(|operation| operation.is_query())
+}
+
+/// Returns the kind of the operation `name` selects (or of the only operation) and the reprinted document.
+fn parse_document(query: &str, name: Option<&str>) -> Option<(Option<OperationKind>, String)> {
+    let document = parse_query::<&str>(query).ok()?;
+
+    let mut operations = document.definitions.iter().filter_map(|definition| match definition {
+        Definition::Operation(operation) => Some(operation),
+        Definition::Fragment(_) => None,
+    });
+    let selected = match name {
+        Some(name) => operations.find(|operation| operation_name(operation) == Some(name)),
+        None => operations.next().filter(|_| operations.next().is_none()),
+    };
+
+    let kind = selected.map(|operation| match operation {
+        OperationDefinition::SelectionSet(_) | OperationDefinition::Query(_) => OperationKind::Query,
+        OperationDefinition::Mutation(_) => OperationKind::Mutation,
+        OperationDefinition::Subscription(_) => OperationKind::Subscription,
+    });
+    Some((kind, document.to_string()))
+}
+
+fn operation_name<'a>(operation: &OperationDefinition<'a, &'a str>) -> Option<&'a str> {
+    match operation {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(query) => query.name,
+        OperationDefinition::Mutation(mutation) => mutation.name,
+        OperationDefinition::Subscription(subscription) => subscription.name,
+    }
+}
+
+/// Rejects operations outside the descriptor's `persisted_queries` allowlist, if it has one.
+pub fn check_allowlist(desc: &ProxyDescriptor, operation: Option<&Operation>) -> Result<(), ApiError> {
+    let Some(allowed) = desc.persisted_queries.as_ref().filter(|_| is_graphql(desc)) else {
+        return Ok(());
+    };
+
+    let hash = operation.and_then(|operation| operation.query_hash.as_deref());
+    match hash {
+        Some(hash) if allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(hash)) => Ok(()),
+        _ => Err(ApiError::OperationNotAllowed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &str = "query Books($first: Int) { books(first: $first) { title } }";
+
+    fn sha256(query: &str) -> String {
+        format!("{:x}", Sha256::digest(query.as_bytes()))
+    }
+
+    fn get(params: &[(&str, &str)]) -> Option<Operation> {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish();
+        Operation::parse(&Method::GET, Some(&query), &[])
+    }
+
+    fn post(body: Value) -> Option<Operation> {
+        Operation::parse(&Method::POST, None, body.to_string().as_bytes())
+    }
+
+    fn descriptor(persisted_queries: Option<Vec<String>>) -> ProxyDescriptor {
+        ProxyDescriptor {
+            service_name: "graphql".into(),
+            capabilities: vec![ParserCapabilities::GraphQl],
+            persisted_queries,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn get_and_post_parse_to_the_same_operation() {
+        let from_get = get(&[
+            ("query", QUERY),
+            ("operationName", "Books"),
+            ("variables", r#"{"first": 10, "after": null}"#),
+        ])
+        .unwrap();
+        let from_post = post(serde_json::json!({
+            "query": QUERY,
+            "operationName": "Books",
+            "variables": { "after": null, "first": 10 },
+        }))
+        .unwrap();
+
+        for operation in [&from_get, &from_post] {
+            assert_eq!(operation.kind, Some(OperationKind::Query));
+            assert_eq!(operation.name.as_deref(), Some("Books"));
+            assert_eq!(operation.query_hash.as_deref(), Some(sha256(QUERY).as_str()));
+        }
+        assert_eq!(from_get.normalized_query, from_post.normalized_query);
+        assert_eq!(from_get.variables, from_post.variables);
+    }
+
+    #[test]
+    fn normalizes_formatting_and_comments() {
+        let compact = get(&[("query", "{ books { title } }")]).unwrap();
+        let spaced = get(&[("query", "# all books\n{\n  books {\n    title\n  }\n}")]).unwrap();
+        assert_eq!(compact.normalized_query, spaced.normalized_query);
+        assert!(compact.is_query());
+    }
+
+    #[test]
+    fn detects_mutations() {
+        let operation = post(serde_json::json!({ "query": "mutation { addBook(title: \"x\") { id } }" })).unwrap();
+        assert_eq!(operation.kind, Some(OperationKind::Mutation));
+        assert!(!operation.is_query());
+
+        // The operation name picks which of several operations runs.
+        let document = "query Books { books { title } } mutation AddBook { addBook(title: \"x\") { id } }";
+        let named = |name: &str| post(serde_json::json!({ "query": document, "operationName": name })).unwrap();
+        assert_eq!(named("AddBook").kind, Some(OperationKind::Mutation));
+        assert_eq!(named("Books").kind, Some(OperationKind::Query));
+        assert_eq!(post(serde_json::json!({ "query": document })).unwrap().kind, None);
+    }
+
+    #[test]
+    fn rejects_mismatched_persisted_hashes() {
+        let extensions = |hash: &str| serde_json::json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } });
+
+        assert!(post(serde_json::json!({ "query": QUERY, "extensions": extensions("0000") })).is_none());
+
+        let upper = sha256(QUERY).to_ascii_uppercase();
+        let matching = post(serde_json::json!({ "query": QUERY, "extensions": extensions(&upper) })).unwrap();
+        assert_eq!(matching.query_hash, Some(sha256(QUERY)));
+    }
+
+    #[test]
+    fn hash_only_requests_are_queries_over_get_only() {
+        let extensions = serde_json::json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256(QUERY) } });
+
+        let posted = post(serde_json::json!({ "extensions": extensions })).unwrap();
+        assert_eq!(posted.kind, None);
+        assert_eq!(posted.normalized_query, None);
+        assert_eq!(posted.query_hash, Some(sha256(QUERY)));
+
+        let fetched = get(&[("extensions", &extensions.to_string())]).unwrap();
+        assert!(fetched.is_query());
+    }
+
+    #[test]
+    fn ignores_requests_that_are_not_graphql() {
+        assert!(get(&[("page", "2")]).is_none());
+        assert!(Operation::parse(&Method::GET, None, &[]).is_none());
+        assert!(Operation::parse(&Method::POST, None, b"[{\"query\": \"{ a }\"}]").is_none());
+        assert!(Operation::parse(&Method::PUT, None, b"{\"query\": \"{ a }\"}").is_none());
+        assert!(post(serde_json::json!({ "query": "{ unclosed" })).is_none());
+    }
+
+    #[test]
+    fn allowlist_accepts_listed_hashes_only() {
+        let operation = get(&[("query", QUERY)]).unwrap();
+        let allowed = descriptor(Some(vec![sha256(QUERY).to_ascii_uppercase()]));
+        assert!(check_allowlist(&allowed, Some(&operation)).is_ok());
+
+        let other = descriptor(Some(vec![sha256("{ other }")]));
+        assert!(matches!(
+            check_allowlist(&other, Some(&operation)),
+            Err(ApiError::OperationNotAllowed)
+        ));
+        assert!(check_allowlist(&allowed, None).is_err());
+    }
+
+    #[test]
+    fn allowlist_applies_to_graphql_services_with_a_list() {
+        assert!(check_allowlist(&descriptor(None), None).is_ok());
+
+        let rest = ProxyDescriptor {
+            capabilities: Vec::new(),
+            ..descriptor(Some(Vec::new()))
+        };
+        assert!(check_allowlist(&rest, None).is_ok());
+    }
+}
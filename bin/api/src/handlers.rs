@@ -1,13 +1,22 @@
-use crate::error::ApiError;
+use crate::cache::{self, CacheEntry, ProxiedResponse, Staleness, StoredRequest};
+use crate::cache_policy;
+use crate::coalesce::DistributedLock;
+use crate::conditional::{self, Preconditions};
+use crate::error::{ApiError, Problem, RequestContext};
+use crate::graphql::{self, Operation};
+use crate::purge;
+use crate::registry::RouteError;
 use crate::state::AppState;
+use crate::vary;
+use async_nats::RequestErrorKind;
 use axum::body::Bytes;
 use axum::extract::{OriginalUri, Path, State};
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
 use axum::response::{IntoResponse, Response as AxumResponse};
-use http::{Request, Response};
-use prtl_messages::BusMessage;
-use redis::AsyncCommands;
-use tracing::{error, info};
+use http::Request;
+use prtl_messages::{BusMessage, HashComponents, ProxyDescriptor, UpstreamPolicy};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
 use url::Url;
 
 pub async fn handle_request(
@@ -17,146 +26,531 @@ pub async fn handle_request(
     Path(path): Path<String>,
     headers: HeaderMap,
     body: Bytes,
+) -> Result<AxumResponse, Problem> {
+    let mut ctx = RequestContext::from_headers(&headers);
+
+    match proxy_request(&state, method, uri, path, headers, body, &mut ctx).await {
+        Ok(response) => Ok(response),
+        Err(e) => Err(ctx.problem(e)),
+    }
+}
+
+/// Client override for the proxy reply timeout, in milliseconds. Capped by the gateway maximum.
+const TIMEOUT_HEADER: &str = "x-prtl-timeout-ms";
+
+/// How often a gateway waiting on another gateway's lock checks the cache.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+async fn proxy_request(
+    state: &AppState,
+    method: Method,
+    uri: Uri,
+    path: String,
+    mut headers: HeaderMap,
+    body: Bytes,
+    ctx: &mut RequestContext,
 ) -> Result<AxumResponse, ApiError> {
+    let started = Instant::now();
     let raw_query = uri.query();
-    let url = parse_url(&path, raw_query)?;
+    let (url, explicit_port) = parse_url(&path, raw_query)?;
 
     let domain = url.domain().ok_or(ApiError::InvalidUrl("No domain".into()))?;
 
     let registry = state.proxy_registry.read().await;
-    let proxy_desc = registry.find_proxy_for_domain(domain).ok_or_else(|| {
-        error!("No proxy available for domain: {}", domain);
-        ApiError::NoParserAvailable
-    })?;
-
-    let service_name = proxy_desc.service_name.clone();
-    let cache_ttl_secs = proxy_desc.cache_ttl.map(|d| d.as_secs()).unwrap_or(3600); // todo: disable caching by default
-    let hash_settings = proxy_desc.hash_settings;
+    let proxy_desc = registry
+        .find_proxy(domain, explicit_port, &method, url.path())
+        .map_err(|e| {
+            match &e {
+                RouteError::NoProxy => error!("No proxy available for domain: {}", domain),
+                RouteError::PortNotAllowed(port) => warn!("Port {} not allowed for {}", port, url),
+                RouteError::NoRoute => warn!("No proxy route for {}", url),
+                RouteError::MethodNotAllowed(_) => warn!("Method {} not allowed for {}", method, url),
+            }
+            ApiError::from(e)
+        })?
+        .clone();
     drop(registry);
 
-    let mut req_builder_for_cache = Request::builder().method(method.as_str()).uri(url.as_str());
+    ctx.service = Some(proxy_desc.service_name.clone());
+    let stale_while_revalidate = proxy_desc.stale_while_revalidate;
+    let stale_if_error = proxy_desc.stale_if_error;
+    let url = upstream_url(url, explicit_port, &proxy_desc.upstream)?;
+    let timeout = request_timeout(&headers, proxy_desc.request_timeout, state)?;
+    let deadline = started + timeout;
+
+    let is_graphql = graphql::is_graphql(&proxy_desc);
+    let operation = match is_graphql {
+        true => Operation::parse(&method, uri.query(), &body),
+        false => None,
+    };
+    graphql::check_allowlist(&proxy_desc, operation.as_ref())?;
+    let is_query = operation.as_ref().is_some_and(Operation::is_query);
+
+    // Mutations and requests that can't be parsed as GraphQL operations are never cached.
+    let cacheable = (method == Method::HEAD || cache_policy::is_stored_method(&proxy_desc, &method))
+        && proxy_desc.cache_ttl.is_some()
+        && (is_query || !is_graphql);
+    // Only requests without side effects may share a response. HEAD is left out as it would hand its empty
+    // body to concurrent GETs. For GraphQL services the operation decides: queries are shared whether sent with
+    // GET or POST, mutations never are, even over GET.
+    let shareable = match is_graphql {
+        true => is_query && (method == Method::GET || method == Method::POST),
+        false => method == Method::GET,
+    };
+    // Conditional requests to cached services are answered by the gateway, from whatever response it ends up with.
+    let preconditions = match cacheable && (method == Method::GET || method == Method::HEAD) {
+        true => Preconditions::take(&mut headers),
+        false => Preconditions::default(),
+    };
+
+    let mut req_builder = Request::builder().method(method.as_str()).uri(url.as_str());
 
     for (name, value) in headers.iter() {
         let name_str = name.as_str();
-        if !name_str.eq_ignore_ascii_case("host") && !name_str.eq_ignore_ascii_case("connection") {
-            req_builder_for_cache = req_builder_for_cache.header(name.as_str(), value.as_bytes());
+        if !name_str.eq_ignore_ascii_case("host")
+            && !name_str.eq_ignore_ascii_case("connection")
+            && !name_str.eq_ignore_ascii_case(TIMEOUT_HEADER)
+        {
+            req_builder = req_builder.header(name.as_str(), value.as_bytes());
         }
     }
 
-    let request_for_cache = req_builder_for_cache.body(body.to_vec()).map_err(|e| {
-        error!("Failed to build request for cache: {}", e);
+    let http_request = req_builder.body(body.to_vec()).map_err(|e| {
+        error!("Failed to build request: {}", e);
         ApiError::InternalError(e.to_string())
     })?;
 
-    let cache_hash = crate::hash::compute_cache_key(&request_for_cache, &hash_settings);
-    let cache_key = format!("proxy:{}:{}", service_name, cache_hash);
+    let primary = crate::hash::compute_cache_key(&http_request, &proxy_desc);
 
-    let mut redis = state.redis.clone();
-    if let Ok(Some(cached_data)) = redis.get::<_, Option<Vec<u8>>>(&cache_key).await
-        && let Ok((status_code, headers_vec, body)) =
-            rmp_serde::from_slice::<(u16, Vec<(String, Vec<u8>)>, Vec<u8>)>(&cached_data)
-    {
-        info!("Cache hit for {} (key: {})", url, cache_key);
-
-        let mut axum_headers = HeaderMap::new();
-        for (name, value) in headers_vec {
-            if let (Ok(header_name), Ok(header_value)) = (
-                axum::http::HeaderName::try_from(name),
-                axum::http::HeaderValue::from_bytes(&value),
-            ) {
-                axum_headers.insert(header_name, header_value);
+    let (cache_key, cached) = if cacheable {
+        let store = state.cache.as_ref();
+        let cache_key = vary::lookup_key(store, &proxy_desc.service_name, &primary, http_request.headers()).await;
+        state.hit_tracker.record(&cache_key);
+        let cached = cache::get(store, &cache_key).await;
+        (cache_key, cached)
+    } else {
+        (cache::key(&proxy_desc.service_name, &primary), None)
+    };
+
+    let fetch = Fetch::new(state, &proxy_desc, primary, cache_key, deadline);
+
+    if let Some(entry) = &cached {
+        if entry.is_fresh() {
+            info!("Cache hit for {} (key: {})", url, fetch.cache_key);
+            return Ok(preconditions.evaluate(entry.to_response(None)).into_response());
+        }
+
+        if shareable && entry.is_stale_within(stale_while_revalidate) {
+            info!("Serving stale {} while revalidating (key: {})", url, fetch.cache_key);
+            let fetch = Fetch {
+                deadline: Instant::now() + timeout,
+                ..fetch
+            };
+            let stale = entry.clone();
+            tokio::spawn(async move {
+                let _ = fetch.revalidate_shared(http_request, &stale).await;
+            });
+            let response = entry.to_response(Some(Staleness::Revalidating));
+            return Ok(preconditions.evaluate(response).into_response());
+        }
+    }
+
+    let result = if shareable {
+        fetch.shared(http_request, cached.as_ref()).await
+    } else {
+        fetch.call_proxy(http_request).await
+    };
+
+    let stale_fallback = cached.filter(|entry| entry.is_stale_within(stale_if_error));
+    match (result, stale_fallback) {
+        (Ok(response), Some(entry)) if response.status.is_server_error() => {
+            warn!("Proxy answered {} for {}, serving stale response", response.status, url);
+            let response = entry.to_response(Some(Staleness::RevalidationFailed));
+            Ok(preconditions.evaluate(response).into_response())
+        }
+        (Err(e), Some(entry)) if is_proxy_failure(&e) => {
+            warn!("Proxy failed for {} ({}), serving stale response", url, e.code());
+            let response = entry.to_response(Some(Staleness::RevalidationFailed));
+            Ok(preconditions.evaluate(response).into_response())
+        }
+        (result, _) => Ok(preconditions.evaluate(result?).into_response()),
+    }
+}
+
+/// Errors after which a stale cached response beats an error page.
+fn is_proxy_failure(error: &ApiError) -> bool {
+    matches!(
+        error,
+        ApiError::NoParserAvailable
+            | ApiError::ProxyTimeout
+            | ApiError::ProxyUnreachable
+            | ApiError::ProtocolError
+            | ApiError::UpstreamError
+            | ApiError::RateLimited { .. }
+    )
+}
+
+/// Outcome of a proxy call shared by concurrent requests, see [`Fetch::shared`].
+#[derive(Clone)]
+pub struct SharedResponse {
+    result: Result<ProxiedResponse, ApiError>,
+    /// Headers of the request the call was made for.
+    request_headers: HeaderMap,
+}
+
+/// Everything needed to fetch a response from the proxy after a cache miss or for a refresh.
+pub struct Fetch {
+    state: AppState,
+    desc: ProxyDescriptor,
+    /// Hash of the request without its `Vary` headers, from which the keys of all variants derive.
+    primary: String,
+    /// Key the response is expected under. The response's own `Vary` decides where it is stored.
+    pub cache_key: String,
+    deadline: Instant,
+}
+
+impl Fetch {
+    pub fn new(
+        state: &AppState,
+        desc: &ProxyDescriptor,
+        primary: String,
+        cache_key: String,
+        deadline: Instant,
+    ) -> Self {
+        Self {
+            state: state.clone(),
+            desc: desc.clone(),
+            primary,
+            cache_key,
+            deadline,
+        }
+    }
+
+    /// Calls the proxy through [`Fetch::coalesced`] once for all concurrent requests with this cache key. Until
+    /// a response has named its `Vary` fields the key can't tell variants apart, so a request whose headers select
+    /// another variant than the shared response gets a call of its own.
+    pub async fn shared(
+        &self,
+        request: Request<Vec<u8>>,
+        previous: Option<&CacheEntry>,
+    ) -> Result<ProxiedResponse, ApiError> {
+        let request_headers = request.headers().clone();
+        let own_request = copy_request(&request);
+        let shared = self
+            .state
+            .in_flight
+            .run(&self.cache_key, async {
+                SharedResponse {
+                    result: self.coalesced(request, previous).await,
+                    request_headers: request_headers.clone(),
+                }
+            })
+            .await;
+
+        match shared.result {
+            Ok(response) if !vary::same_variant(&response.headers, &shared.request_headers, &request_headers) => {
+                info!(
+                    "Shared response is another variant, fetching again (key: {})",
+                    self.cache_key
+                );
+                self.exchange(own_request, previous).await
             }
+            result => result,
         }
+    }
 
-        return Ok((
-            StatusCode::from_u16(status_code).unwrap_or(StatusCode::OK),
-            axum_headers,
-            body,
-        )
-            .into_response());
+    /// [`Fetch::revalidate`], sharing the call with concurrent requests for this cache key. Meant for background
+    /// refreshes, which only care whether the store got a fresh entry.
+    pub async fn revalidate_shared(
+        &self,
+        request: Request<Vec<u8>>,
+        entry: &CacheEntry,
+    ) -> Result<ProxiedResponse, ApiError> {
+        let request_headers = request.headers().clone();
+        self.state
+            .in_flight
+            .run(&self.cache_key, async {
+                SharedResponse {
+                    result: self.revalidate(request, entry).await,
+                    request_headers,
+                }
+            })
+            .await
+            .result
     }
 
-    let mut req_builder = Request::builder().method(method.as_str()).uri(url.as_str());
+    /// With `COALESCE_DISTRIBUTED_LOCK` on, only the gateway holding the store's lock for the cache key calls the
+    /// proxy; the others wait for the cached response and only fetch it themselves if none shows up. An expired
+    /// `previous` entry is revalidated rather than fetched again.
+    async fn coalesced(
+        &self,
+        request: Request<Vec<u8>>,
+        previous: Option<&CacheEntry>,
+    ) -> Result<ProxiedResponse, ApiError> {
+        if !self.state.distributed_lock {
+            return self.exchange(request, previous).await;
+        }
 
-    for (name, value) in headers.iter() {
-        let name_str = name.as_str();
-        if !name_str.eq_ignore_ascii_case("host") && !name_str.eq_ignore_ascii_case("connection") {
-            req_builder = req_builder.header(name.as_str(), value.as_bytes());
+        let store = self.state.cache.as_ref();
+        let lock_key = format!("lock:{}", self.cache_key);
+        let ttl = self.deadline.saturating_duration_since(Instant::now());
+
+        match DistributedLock::try_acquire(self.state.cache.clone(), lock_key.clone(), ttl).await {
+            Ok(Some(lock)) => {
+                let response = self.exchange(request, previous).await;
+                lock.release().await;
+                return response;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Failed to acquire lock {}: {}", lock_key, e);
+                return self.exchange(request, previous).await;
+            }
+        }
+
+        loop {
+            tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+
+            if let Some(entry) = cache::get(store, &self.cache_key).await
+                && entry.is_fresh()
+            {
+                info!("Cache filled by another gateway (key: {})", self.cache_key);
+                return Ok(entry.to_response(None));
+            }
+            if Instant::now() >= self.deadline {
+                return Err(ApiError::ProxyTimeout);
+            }
+            if !DistributedLock::is_held(store, &lock_key).await.unwrap_or(false) {
+                break;
+            }
         }
+
+        // The holder gave up or got an uncacheable response.
+        self.exchange(request, previous).await
     }
 
-    let http_request = req_builder.body(body.to_vec()).map_err(|e| {
-        error!("Failed to build request: {}", e);
-        ApiError::InternalError(e.to_string())
-    })?;
+    pub async fn call_proxy(&self, request: Request<Vec<u8>>) -> Result<ProxiedResponse, ApiError> {
+        self.exchange(request, None).await
+    }
 
-    let rpc_subject = BusMessage::subject_for_rpc(&service_name);
-    let payload = rmp_serde::to_vec_named(&BusMessage::ProxyRequest(http_request)).map_err(|e| {
-        error!("Serialization error: {}", e);
-        ApiError::InternalError(e.to_string())
-    })?;
+    /// Sends `request` with the validators of `entry`, so an unchanged upstream only costs a 304. Either way the
+    /// full response is returned and the entry is stored again.
+    pub async fn revalidate(&self, request: Request<Vec<u8>>, entry: &CacheEntry) -> Result<ProxiedResponse, ApiError> {
+        self.exchange(request, Some(entry)).await
+    }
+
+    async fn exchange(
+        &self,
+        mut request: Request<Vec<u8>>,
+        previous: Option<&CacheEntry>,
+    ) -> Result<ProxiedResponse, ApiError> {
+        let service_name = &self.desc.service_name;
+        let url = request.uri().to_string();
+
+        let target = self
+            .state
+            .proxy_registry
+            .read()
+            .await
+            .select_target(service_name)
+            .ok_or_else(|| {
+                error!("No live instance of {} available", service_name);
+                ApiError::NoParserAvailable
+            })?;
+        let rpc_subject = target.subject.clone();
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            warn!("Deadline for {} passed before reaching the proxy", url);
+            return Err(ApiError::ProxyTimeout);
+        }
 
-    let response = state
-        .nats
-        .request(rpc_subject.clone(), payload.into())
-        .await
+        let mut stored_request = StoredRequest::new(&request);
+        let graphql_cacheable = graphql::is_cacheable(&self.desc, &request);
+        let method = request.method().clone();
+        let request_headers = request.headers().clone();
+        if let Some(entry) = previous {
+            conditional::add_validators(request.headers_mut(), &entry.header_map());
+        }
+        let payload = rmp_serde::to_vec_named(&BusMessage::ProxyRequest {
+            request,
+            timeout: Some(remaining),
+        })
         .map_err(|e| {
-            error!("NATS request to {} failed: {}", rpc_subject, e);
+            error!("Serialization error: {}", e);
             ApiError::InternalError(e.to_string())
         })?;
 
-    let proxy_response: BusMessage = rmp_serde::from_slice(&response.payload).map_err(|e| {
-        error!("Failed to deserialize proxy response: {}", e);
-        ApiError::InternalError(e.to_string())
-    })?;
-
-    let http_response = match proxy_response {
-        BusMessage::ProxyResponse(resp) => {
-            info!("Proxy response OK, status={}", resp.status());
-            resp
+        let max_payload = self.state.nats.server_info().max_payload;
+        if payload.len() > max_payload {
+            warn!(
+                "Request for {} is {} bytes, over the bus limit of {}",
+                url,
+                payload.len(),
+                max_payload
+            );
+            return Err(ApiError::PayloadTooLarge {
+                size: payload.len(),
+                limit: max_payload,
+            });
         }
-        _ => {
-            error!("Unexpected response type from proxy");
-            return Err(ApiError::InternalError("Unexpected response".into()));
+
+        let response = self
+            .state
+            .nats
+            .send_request(
+                rpc_subject.clone(),
+                async_nats::Request::new()
+                    .payload(payload.into())
+                    .timeout(Some(remaining)),
+            )
+            .await
+            .map_err(|e| {
+                error!("NATS request to {} failed: {}", rpc_subject, e);
+                match e.kind() {
+                    RequestErrorKind::TimedOut => ApiError::ProxyTimeout,
+                    RequestErrorKind::NoResponders | RequestErrorKind::Other => ApiError::ProxyUnreachable,
+                }
+            })?;
+        drop(target);
+
+        let proxy_response: BusMessage = rmp_serde::from_slice(&response.payload).map_err(|e| {
+            error!("Failed to deserialize proxy response: {}", e);
+            ApiError::ProtocolError
+        })?;
+
+        let mut http_response = match proxy_response {
+            BusMessage::ProxyResponse(resp) => {
+                info!("Proxy response OK, status={}", resp.status());
+                ProxiedResponse::from(resp)
+            }
+            BusMessage::ProxyError(e) => {
+                warn!("Proxy {} reported an error: {}", service_name, e);
+                return Err(e.into());
+            }
+            _ => {
+                error!("Unexpected response type from proxy");
+                return Err(ApiError::ProtocolError);
+            }
+        };
+
+        let mut tags = purge::take_tags(&mut http_response.headers);
+
+        if let Some(entry) = previous
+            && http_response.status == StatusCode::NOT_MODIFIED
+        {
+            info!("Cache entry {} revalidated by the proxy", self.cache_key);
+            http_response = entry.revalidated(&http_response.headers);
+            if tags.is_empty() {
+                tags = entry.tags.clone();
+            }
         }
-    };
 
-    if http_response.status().is_success() {
-        let status_code = http_response.status().as_u16();
-        let headers_vec: Vec<(String, Vec<u8>)> = http_response
-            .headers()
-            .iter()
-            .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
-            .collect();
-        let body = http_response.body().clone();
+        let storable = graphql_cacheable && cache_policy::fits_body(&self.desc, http_response.body.len());
+        let ttl = match storable {
+            true => cache_policy::ttl(
+                &self.desc,
+                &method,
+                &request_headers,
+                http_response.status,
+                &http_response.headers,
+            ),
+            false => None,
+        };
+        if let Some(ttl) = ttl {
+            let store = self.state.cache.as_ref();
+            let stale_window = cache::stale_window(&self.desc);
+            let expiry = ttl + stale_window;
+            // Cacheable responses never carry `Vary: *`.
+            let fields = vary::fields(&http_response.headers).unwrap_or_default();
+            let cache_key = vary::variant_key(service_name, &self.primary, &fields, &request_headers);
+
+            // Keep the headers that select the key with the entry, so refreshes can replay them.
+            let hash_headers = self.desc.hash_settings.contains(HashComponents::HEADERS);
+            if !self.desc.hash_settings.contains(HashComponents::BODY) && !graphql::is_graphql(&self.desc) {
+                stored_request.body.clear();
+            }
+            stored_request.retain_headers(|name| {
+                hash_headers
+                    || fields.iter().any(|field| field == name)
+                    || self
+                        .desc
+                        .key_headers
+                        .iter()
+                        .any(|header| header.eq_ignore_ascii_case(name))
+            });
 
-        if let Ok(cached_data) = rmp_serde::to_vec(&(status_code, headers_vec, body)) {
-            let _: Result<(), _> = redis.set_ex(&cache_key, cached_data, cache_ttl_secs).await;
+            conditional::ensure_validator(&mut http_response);
+            let entry = CacheEntry::new(&http_response, ttl, Some(stored_request), tags.clone());
+            let mut indexes = purge::tag_indexes(service_name, &tags);
+            indexes.push(vary::variants_key(service_name, &self.primary));
+            cache::put(
+                store,
+                &self.state.entry_format,
+                &cache_key,
+                &entry,
+                stale_window,
+                indexes,
+            )
+            .await;
+
+            if let Err(e) = vary::record(store, service_name, &self.primary, &fields, expiry).await {
+                warn!("Failed to record variants of {}: {}", cache_key, e);
+            }
         }
-    }
 
-    Ok(convert_response_to_axum(http_response))
+        Ok(http_response)
+    }
 }
 
-fn convert_response_to_axum(response: Response<Vec<u8>>) -> AxumResponse {
-    let (parts, body) = response.into_parts();
-    let mut axum_headers = HeaderMap::new();
+fn copy_request(request: &Request<Vec<u8>>) -> Request<Vec<u8>> {
+    let mut copy = Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
 
-    for (name, value) in parts.headers.iter() {
-        if let Ok(axum_value) = axum::http::HeaderValue::from_bytes(value.as_bytes()) {
-            axum_headers.insert(name.clone(), axum_value);
+/// Picks the reply timeout: the client's header, else the proxy's own setting, else the gateway default, never
+/// more than the gateway maximum.
+fn request_timeout(
+    headers: &HeaderMap,
+    proxy_timeout: Option<Duration>,
+    state: &AppState,
+) -> Result<Duration, ApiError> {
+    let client_timeout = match headers.get(TIMEOUT_HEADER) {
+        Some(value) => {
+            let millis = value
+                .to_str()
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|millis| *millis > 0)
+                .ok_or_else(|| ApiError::InvalidTimeout(format!("{} must be a positive integer", TIMEOUT_HEADER)))?;
+            Some(Duration::from_millis(millis))
         }
-    }
+        None => None,
+    };
 
-    (parts.status, axum_headers, body).into_response()
+    Ok(state.request_timeouts.resolve(client_timeout.or(proxy_timeout)))
 }
 
-fn parse_url(path: &str, raw_query: Option<&str>) -> Result<Url, ApiError> {
+/// Parses the public `/{host[:port]}/{path}` syntax into an https URL for `host` and the explicitly requested
+/// port, if any. The final upstream URL depends on the proxy's [`UpstreamPolicy`], see [`upstream_url`].
+pub fn parse_url(path: &str, raw_query: Option<&str>) -> Result<(Url, Option<u16>), ApiError> {
     let path = path.trim_start_matches('/');
 
     let parts: Vec<&str> = path.splitn(2, '/').collect();
 
-    let domain = parts.first().ok_or(ApiError::InvalidPath)?;
+    let authority = parts.first().ok_or(ApiError::InvalidPath)?;
+    let (domain, port) = match authority.rsplit_once(':') {
+        Some((domain, port)) if !authority.ends_with(']') => {
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| ApiError::InvalidUrl(format!("Invalid port: {}", port)))?;
+            (domain, Some(port))
+        }
+        _ => (*authority, None),
+    };
     let resource_path = parts.get(1).map(|s| format!("/{}", s)).unwrap_or_default();
 
     let mut url_str = format!("https://{}{}", domain, resource_path);
@@ -168,5 +562,26 @@ fn parse_url(path: &str, raw_query: Option<&str>) -> Result<Url, ApiError> {
         url_str.push_str(query);
     }
 
-    Url::parse(&url_str).map_err(|e| ApiError::InvalidUrl(e.to_string()))
+    let url = Url::parse(&url_str).map_err(|e| ApiError::InvalidUrl(e.to_string()))?;
+
+    Ok((url, port))
+}
+
+pub fn upstream_url(mut url: Url, explicit_port: Option<u16>, policy: &UpstreamPolicy) -> Result<Url, ApiError> {
+    if let Some(origin) = &policy.origin {
+        let mut upstream = Url::parse(origin).map_err(|e| {
+            error!("Invalid upstream origin {}: {}", origin, e);
+            ApiError::InternalError("Invalid upstream origin".into())
+        })?;
+        upstream.set_path(url.path());
+        upstream.set_query(url.query());
+        return Ok(upstream);
+    }
+
+    let invalid = |_| ApiError::InvalidUrl("Cannot build upstream URL".into());
+    url.set_scheme(policy.scheme.as_str()).map_err(invalid)?;
+    url.set_port(Some(policy.effective_port(explicit_port)))
+        .map_err(invalid)?;
+
+    Ok(url)
 }
@@ -1,20 +1,61 @@
-use http::Request;
-use prtl_messages::HashComponents;
+use crate::graphql::{self, Operation};
+use http::{Method, Request};
+use prtl_messages::{BodyCanonicalization, HashComponents, ProxyDescriptor};
+use serde_json::Value;
 
-pub fn compute_cache_key<B>(request: &Request<B>, settings: &HashComponents) -> String
+/// Primary cache key hash of a request, covering the components selected by the descriptor's `hash_settings`.
+/// Responses that vary on request headers are stored under a secondary key derived from it, see [`crate::vary`].
+///
+/// GraphQL operations are keyed by their normalized document and variables in place of the method, query string
+/// and body, so the same query sent by GET or POST, or formatted differently, shares an entry.
+pub fn compute_cache_key<B>(request: &Request<B>, desc: &ProxyDescriptor) -> String
 where
     B: AsRef<[u8]>,
 {
+    let operation = match graphql::is_graphql(desc) {
+        true => Operation::from_request(request),
+        false => None,
+    };
+    let settings = desc.hash_settings;
     let mut hasher = blake3::Hasher::new();
 
+    if let Some(operation) = &operation {
+        let document = match &operation.normalized_query {
+            Some(query) => query.as_str(),
+            None => operation.query_hash.as_deref().unwrap_or_default(),
+        };
+        update_field(&mut hasher, b"graphql", document.as_bytes());
+        update_field(
+            &mut hasher,
+            b"operation",
+            operation.name.as_deref().unwrap_or_default().as_bytes(),
+        );
+        update_field(&mut hasher, b"variables", &operation.variables);
+    }
+
+    if settings.contains(HashComponents::METHOD) && operation.is_none() {
+        // HEAD is answered from GET entries.
+        let method = match request.method() {
+            &Method::HEAD => &Method::GET,
+            method => method,
+        };
+        update_field(&mut hasher, b"method", method.as_str().as_bytes());
+    }
+
+    if settings.contains(HashComponents::HOST) {
+        let host = request.uri().authority().map(|a| a.as_str()).unwrap_or_default();
+        update_field(&mut hasher, b"host", host.as_bytes());
+    }
+
     if settings.contains(HashComponents::URL) {
-        hasher.update(request.uri().path().as_bytes());
+        update_field(&mut hasher, b"path", request.uri().path().as_bytes());
     }
 
     if settings.contains(HashComponents::QUERY)
+        && operation.is_none()
         && let Some(query) = request.uri().query()
     {
-        hasher.update(query.as_bytes());
+        update_field(&mut hasher, b"query", query.as_bytes());
     }
 
     if settings.contains(HashComponents::HEADERS) {
@@ -23,15 +64,192 @@ where
             .iter()
             .map(|(k, v)| (k.as_str(), v.as_bytes()))
             .collect();
-        headers.sort_by_key(|(k, _)| *k);
+        headers.sort();
 
         for (key, value) in headers {
-            hasher.update(key.as_bytes());
-            hasher.update(b":");
-            hasher.update(value);
-            hasher.update(b"\n");
+            update_field(&mut hasher, b"header", key.as_bytes());
+            update_prefixed(&mut hasher, value);
+        }
+    } else {
+        let mut names: Vec<String> = desc.key_headers.iter().map(|name| name.to_ascii_lowercase()).collect();
+        names.sort();
+        names.dedup();
+
+        for name in names {
+            update_field(&mut hasher, b"header", name.as_bytes());
+            for value in request.headers().get_all(name.as_str()) {
+                update_prefixed(&mut hasher, value.as_bytes());
+            }
+        }
+    }
+
+    if settings.contains(HashComponents::BODY) && operation.is_none() {
+        let body = request.body().as_ref();
+        match desc.body_canonicalization {
+            BodyCanonicalization::Raw => update_field(&mut hasher, b"body", body),
+            BodyCanonicalization::Json => match serde_json::from_slice::<Value>(body) {
+                Ok(value) => {
+                    let mut canonical = Vec::with_capacity(body.len());
+                    write_canonical_json(&value, &mut canonical);
+                    update_field(&mut hasher, b"body", &canonical);
+                }
+                Err(_) => update_field(&mut hasher, b"body", body),
+            },
         }
     }
 
     hasher.finalize().to_hex().to_string()
 }
+
+/// Hashes a named component. Names and values are length-prefixed so adjacent components can't run into each
+/// other, e.g. path `a` with query `bc` and path `ab` with query `c`.
+pub fn update_field(hasher: &mut blake3::Hasher, name: &[u8], value: &[u8]) {
+    update_prefixed(hasher, name);
+    update_prefixed(hasher, value);
+}
+
+pub fn update_prefixed(hasher: &mut blake3::Hasher, bytes: &[u8]) {
+    hasher.update(&(bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+/// Serializes JSON with object keys sorted and without insignificant whitespace, so equivalent documents hash
+/// the same.
+pub fn write_canonical_json(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+
+            out.push(b'{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_canonical_json(&Value::String(key.clone()), out);
+                out.push(b':');
+                write_canonical_json(value, out);
+            }
+            out.push(b'}');
+        }
+        Value::Array(values) => {
+            out.push(b'[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_canonical_json(value, out);
+            }
+            out.push(b']');
+        }
+        scalar => {
+            let _ = serde_json::to_writer(&mut *out, scalar);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prtl_messages::ParserCapabilities;
+
+    fn descriptor(hash_settings: HashComponents) -> ProxyDescriptor {
+        ProxyDescriptor {
+            service_name: "test".into(),
+            hash_settings,
+            ..Default::default()
+        }
+    }
+
+    fn request(method: Method, uri: &str, body: &str) -> Request<Vec<u8>> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body.as_bytes().to_vec())
+            .unwrap()
+    }
+
+    fn key(desc: &ProxyDescriptor, request: &Request<Vec<u8>>) -> String {
+        compute_cache_key(request, desc)
+    }
+
+    #[test]
+    fn fields_are_length_prefixed() {
+        let hash = |fields: &[(&[u8], &[u8])]| {
+            let mut hasher = blake3::Hasher::new();
+            for (name, value) in fields {
+                update_field(&mut hasher, name, value);
+            }
+            hasher.finalize()
+        };
+
+        assert_ne!(
+            hash(&[(b"x", b"ab"), (b"y", b"c")]),
+            hash(&[(b"x", b"a"), (b"y", b"bc")])
+        );
+        assert_ne!(hash(&[(b"ab", b"c")]), hash(&[(b"a", b"bc")]));
+    }
+
+    #[test]
+    fn adjacent_components_do_not_collide() {
+        let desc = descriptor(HashComponents::URL | HashComponents::QUERY);
+        assert_ne!(
+            key(&desc, &request(Method::GET, "https://example.com/ab?c", "")),
+            key(&desc, &request(Method::GET, "https://example.com/a?bc", ""))
+        );
+
+        let desc = ProxyDescriptor {
+            key_headers: vec!["x-a".into(), "x-b".into()],
+            ..descriptor(HashComponents::URL)
+        };
+        let with_headers = |a: &str, b: &str| {
+            let mut request = request(Method::GET, "https://example.com/", "");
+            request.headers_mut().insert("x-a", a.parse().unwrap());
+            request.headers_mut().insert("x-b", b.parse().unwrap());
+            key(&desc, &request)
+        };
+        assert_ne!(with_headers("ab", "c"), with_headers("a", "bc"));
+    }
+
+    #[test]
+    fn head_shares_the_get_key() {
+        let desc = descriptor(HashComponents::METHOD | HashComponents::URL);
+        let get = key(&desc, &request(Method::GET, "https://example.com/a", ""));
+        assert_eq!(get, key(&desc, &request(Method::HEAD, "https://example.com/a", "")));
+        assert_ne!(get, key(&desc, &request(Method::POST, "https://example.com/a", "")));
+    }
+
+    #[test]
+    fn json_bodies_are_canonicalized() {
+        let desc = ProxyDescriptor {
+            body_canonicalization: BodyCanonicalization::Json,
+            ..descriptor(HashComponents::URL | HashComponents::BODY)
+        };
+        let body = |body| key(&desc, &request(Method::POST, "https://example.com/a", body));
+
+        assert_eq!(
+            body(r#"{"b": [1, 2], "a": {"y": 1, "x": 2}}"#),
+            body(r#"{"a":{"x":2,"y":1},"b":[1,2]}"#)
+        );
+        assert_ne!(body(r#"{"b": [2, 1]}"#), body(r#"{"b": [1, 2]}"#));
+    }
+
+    #[test]
+    fn graphql_queries_share_a_key_across_methods() {
+        let desc = ProxyDescriptor {
+            capabilities: vec![ParserCapabilities::GraphQl],
+            ..descriptor(HashComponents::URL | HashComponents::QUERY | HashComponents::BODY)
+        };
+        let get = request(
+            Method::GET,
+            "https://example.com/graphql?query=%7B%20user%20%7B%20id%20%7D%20%7D",
+            "",
+        );
+        let post = request(
+            Method::POST,
+            "https://example.com/graphql",
+            r#"{"query": "{ user {\n id\n} }"}"#,
+        );
+        assert_eq!(key(&desc, &get), key(&desc, &post));
+    }
+}
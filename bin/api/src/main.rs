@@ -1,18 +1,36 @@
+use crate::coalesce::SingleFlight;
 use crate::handlers::handle_request;
-use crate::registry::ProxyRegistry;
-use crate::state::AppState;
+use crate::popularity::HitTracker;
+use crate::registry::{LoadBalancing, ProxyRegistry, RegistrationError};
+use crate::state::{AppState, RequestTimeouts};
+use crate::store::{CacheBackend, CacheStore, MemoryStore, RedisStore, TieredStore};
 use axum::Router;
 use axum::routing::any;
-use prtl_messages::BusMessage;
+use prtl_messages::{BusMessage, RegisterProxyReply, Subjects};
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{error, info, warn};
 
+mod admin;
+mod cache;
+mod cache_policy;
 mod cache_refresh;
+mod coalesce;
+mod conditional;
+mod domains;
+mod entry_format;
+mod env;
 mod error;
+mod graphql;
 mod handlers;
 mod hash;
+mod popularity;
+mod purge;
 mod registry;
+mod routes;
 mod state;
+mod store;
+mod vary;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -25,44 +43,173 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let nats_addr = std::env::var("NATS_ADDR").unwrap_or_else(|_| "nats://localhost:4222".into());
     let redis_addr = std::env::var("REDIS_ADDR").unwrap_or_else(|_| "redis://localhost:6379".into());
     let bind_addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:80".into());
+    let load_balancing = match std::env::var("PROXY_LOAD_BALANCING") {
+        Ok(v) => v.parse::<LoadBalancing>()?,
+        Err(_) => LoadBalancing::default(),
+    };
+    let max_missed_heartbeats = env::var("PROXY_MAX_MISSED_HEARTBEATS").unwrap_or(3);
+    let subjects = std::env::var("PRTL_SUBJECT_PREFIX")
+        .map(Subjects::new)
+        .unwrap_or_default();
+    let request_timeouts = RequestTimeouts {
+        default: env::var("PROXY_REQUEST_TIMEOUT_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(30)),
+        max: env::var("PROXY_MAX_REQUEST_TIMEOUT_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(300)),
+    };
+
+    let cache_backend = match std::env::var("CACHE_BACKEND") {
+        Ok(v) => v.parse::<CacheBackend>()?,
+        Err(_) => CacheBackend::default(),
+    };
+    let memory_max_bytes = env::var("CACHE_MEMORY_MAX_BYTES").unwrap_or(256 * 1024 * 1024);
+    let l1_max_bytes: u64 = env::var("CACHE_L1_MAX_BYTES").unwrap_or(64 * 1024 * 1024);
+    let l1_ttl = env::var("CACHE_L1_TTL_SECS")
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(10));
+
+    let entry_format = entry_format::EntryFormat::from_env();
+
+    let distributed_lock = env::var("COALESCE_DISTRIBUTED_LOCK").unwrap_or(false);
 
     info!("Connecting to NATS at {}", nats_addr);
     let nats = async_nats::connect(&nats_addr).await?;
 
-    info!("Connecting to Redis/DragonflyDB at {}", redis_addr);
-    let redis_client = redis::Client::open(redis_addr)?;
-    let redis_conn = redis::aio::ConnectionManager::new(redis_client).await?;
+    let mut tiered = None;
+    let cache: Arc<dyn CacheStore> = match cache_backend {
+        CacheBackend::Redis => {
+            info!("Connecting to Redis/DragonflyDB at {}", redis_addr);
+            let redis: Arc<dyn CacheStore> = Arc::new(RedisStore::connect(&redis_addr).await?);
+
+            if l1_max_bytes > 0 && !l1_ttl.is_zero() {
+                info!("Using L1 cache of up to {} bytes for {:?}", l1_max_bytes, l1_ttl);
+                let store = Arc::new(TieredStore::new(
+                    redis,
+                    l1_max_bytes,
+                    l1_ttl,
+                    uuid::Uuid::new_v4().to_string(),
+                ));
+                tiered = Some(store.clone());
+                store
+            } else {
+                redis
+            }
+        }
+        CacheBackend::Memory => {
+            info!("Using in-memory cache of up to {} bytes", memory_max_bytes);
+            Arc::new(MemoryStore::new(memory_max_bytes))
+        }
+    };
 
     info!("Broadcasting discovery request");
-    let discovery_subject = BusMessage::subject_for_discovery();
+    let discovery_subject = subjects.discovery();
     let discovery_payload = rmp_serde::to_vec_named(&BusMessage::Discovery)?;
     nats.publish(discovery_subject, discovery_payload.into()).await?;
 
-    let proxy_registry = Arc::new(tokio::sync::RwLock::new(ProxyRegistry::default()));
+    let cache_refresh_config = cache_refresh::CacheRefreshConfig::from_env();
+    let proxy_registry = Arc::new(tokio::sync::RwLock::new(ProxyRegistry::new(
+        load_balancing,
+        subjects.clone(),
+    )));
 
     let state = AppState {
         nats: nats.clone(),
-        redis: redis_conn,
+        cache,
+        tier_stats: tiered.as_ref().map(|store| store.stats()),
+        entry_format,
         proxy_registry: proxy_registry.clone(),
+        request_timeouts,
+        in_flight: Arc::new(SingleFlight::default()),
+        distributed_lock,
+        hit_tracker: HitTracker::new(cache_refresh_config.hit_window),
     };
 
-    tokio::spawn(listen_for_proxy_registrations(nats.clone(), proxy_registry.clone()));
+    tokio::spawn(listen_for_proxy_registrations(
+        nats.clone(),
+        subjects.clone(),
+        proxy_registry.clone(),
+    ));
+    tokio::spawn(listen_for_proxy_heartbeats(
+        nats.clone(),
+        subjects.clone(),
+        proxy_registry.clone(),
+    ));
+    tokio::spawn(listen_for_proxy_deregistrations(
+        nats.clone(),
+        subjects.clone(),
+        proxy_registry.clone(),
+    ));
+    tokio::spawn(listen_for_cache_invalidations(
+        nats.clone(),
+        subjects.clone(),
+        proxy_registry.clone(),
+        state.cache.clone(),
+    ));
+    if let Some(store) = tiered {
+        tokio::spawn(
+            store
+                .clone()
+                .broadcast_evictions(nats.clone(), subjects.cache_evictions()),
+        );
+        tokio::spawn(listen_for_cache_evictions(nats.clone(), subjects.clone(), store));
+    }
+    tokio::spawn(evict_stale_proxies(proxy_registry.clone(), max_missed_heartbeats));
+
+    tokio::spawn(state.hit_tracker.clone().flush_periodically(state.cache.clone()));
 
-    let cache_refresh_config = cache_refresh::CacheRefreshConfig::default();
-    let cache_refresh_service = cache_refresh::CacheRefreshService::new(state.redis.clone(), cache_refresh_config);
+    let cache_refresh_service = cache_refresh::CacheRefreshService::new(state.clone(), cache_refresh_config);
     tokio::spawn(cache_refresh_service.run());
 
-    let app = Router::new().route("/{*path}", any(handle_request)).with_state(state);
+    let mut app = Router::new().route("/{*path}", any(handle_request));
+    match std::env::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => app = app.merge(admin::router(token)),
+        _ => info!("ADMIN_TOKEN not set, admin endpoints disabled"),
+    }
+    let (hit_tracker, cache) = (state.hit_tracker.clone(), state.cache.clone());
+    let app = app.with_state(state);
 
     info!("Starting server on {}", bind_addr);
     let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    info!("Shutting down");
+    hit_tracker.flush(cache.as_ref()).await;
 
     Ok(())
 }
 
-async fn listen_for_proxy_registrations(nats: async_nats::Client, registry: Arc<tokio::sync::RwLock<ProxyRegistry>>) {
-    let mut sub = match nats.subscribe("mirror.proxy.*.register").await {
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+async fn listen_for_proxy_registrations(
+    nats: async_nats::Client,
+    subjects: Subjects,
+    registry: Arc<tokio::sync::RwLock<ProxyRegistry>>,
+) {
+    let mut sub = match nats.subscribe(subjects.register_all()).await {
         Ok(s) => s,
         Err(e) => {
             error!("Failed to subscribe to proxy registrations: {}", e);
@@ -75,13 +222,208 @@ async fn listen_for_proxy_registrations(nats: async_nats::Client, registry: Arc<
     while let Some(msg) = futures_util::stream::StreamExt::next(&mut sub).await {
         match rmp_serde::from_slice::<BusMessage>(&msg.payload) {
             Ok(BusMessage::RegisterParser(req)) => {
-                let mut reg = registry.write().await;
-                reg.register(req.descriptor);
+                let service_name = req.descriptor.service_name.clone();
+                let result = registry.write().await.register(req);
+                if let Err(e) = &result {
+                    warn!("Rejected registration of proxy {}: {}", service_name, e);
+                }
+                reply_to_registration(&nats, msg.reply, &service_name, result).await;
             }
             Err(e) => {
-                warn!("Failed to deserialize registration message: {}", e);
+                // Still answer, so proxies speaking another protocol version learn why they aren't registered
+                let error = RegistrationError::undecodable(&msg.payload, e);
+                warn!("Rejected undecodable registration message: {}", error);
+                reply_to_registration(&nats, msg.reply, "(undecodable)", Err(error)).await;
             }
             _ => {}
         }
     }
 }
+
+async fn reply_to_registration(
+    nats: &async_nats::Client,
+    reply_subject: Option<async_nats::Subject>,
+    service_name: &str,
+    result: Result<(), RegistrationError>,
+) {
+    let Some(reply_subject) = reply_subject else {
+        return;
+    };
+
+    let reply = RegisterProxyReply {
+        accepted: result.is_ok(),
+        reason: result.err().map(|e| e.to_string()),
+    };
+    match rmp_serde::to_vec_named(&BusMessage::RegisterParserReply(reply)) {
+        Ok(payload) => {
+            if let Err(e) = nats.publish(reply_subject, payload.into()).await {
+                error!("Failed to reply to registration of proxy {}: {}", service_name, e);
+            }
+        }
+        Err(e) => error!("Failed to serialize registration reply: {}", e),
+    }
+}
+
+async fn listen_for_proxy_heartbeats(
+    nats: async_nats::Client,
+    subjects: Subjects,
+    registry: Arc<tokio::sync::RwLock<ProxyRegistry>>,
+) {
+    let mut sub = match nats.subscribe(subjects.heartbeat_all()).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to subscribe to proxy heartbeats: {}", e);
+            return;
+        }
+    };
+
+    info!("Listening for proxy heartbeats");
+
+    while let Some(msg) = futures_util::stream::StreamExt::next(&mut sub).await {
+        match rmp_serde::from_slice::<BusMessage>(&msg.payload) {
+            Ok(BusMessage::Heartbeat(beat)) => {
+                if registry.write().await.heartbeat(&beat.service_name, &beat.instance_id) {
+                    continue;
+                }
+
+                // Most likely evicted after a network hiccup; ask just this instance to register again
+                info!(
+                    "Heartbeat from unregistered proxy {} (instance {}), requesting registration",
+                    beat.service_name, beat.instance_id
+                );
+                let payload = match rmp_serde::to_vec_named(&BusMessage::Discovery) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        error!("Failed to serialize discovery request: {}", e);
+                        continue;
+                    }
+                };
+                let subject = subjects.instance_discovery(&beat.service_name, &beat.instance_id);
+                if let Err(e) = nats.publish(subject, payload.into()).await {
+                    error!("Failed to send discovery request: {}", e);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to deserialize heartbeat message: {}", e);
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn listen_for_proxy_deregistrations(
+    nats: async_nats::Client,
+    subjects: Subjects,
+    registry: Arc<tokio::sync::RwLock<ProxyRegistry>>,
+) {
+    let mut sub = match nats.subscribe(subjects.deregister_all()).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to subscribe to proxy deregistrations: {}", e);
+            return;
+        }
+    };
+
+    info!("Listening for proxy deregistrations");
+
+    while let Some(msg) = futures_util::stream::StreamExt::next(&mut sub).await {
+        match rmp_serde::from_slice::<BusMessage>(&msg.payload) {
+            Ok(BusMessage::Deregister(req)) => {
+                let deregistered = registry.write().await.deregister(&req.service_name, &req.instance_id);
+                if !deregistered {
+                    warn!(
+                        "Ignoring deregistration of unknown proxy {} (instance {})",
+                        req.service_name, req.instance_id
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to deserialize deregistration message: {}", e);
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn listen_for_cache_invalidations(
+    nats: async_nats::Client,
+    subjects: Subjects,
+    registry: Arc<tokio::sync::RwLock<ProxyRegistry>>,
+    cache: Arc<dyn CacheStore>,
+) {
+    let mut sub = match nats.subscribe(subjects.invalidate_all()).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to subscribe to cache invalidations: {}", e);
+            return;
+        }
+    };
+
+    info!("Listening for cache invalidations");
+
+    while let Some(msg) = futures_util::stream::StreamExt::next(&mut sub).await {
+        let req = match rmp_serde::from_slice::<BusMessage>(&msg.payload) {
+            Ok(BusMessage::Invalidate(req)) => req,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Failed to deserialize invalidation message: {}", e);
+                continue;
+            }
+        };
+
+        let Some(descriptor) = registry.read().await.descriptor(&req.service_name).cloned() else {
+            warn!("Ignoring invalidation from unknown proxy {}", req.service_name);
+            continue;
+        };
+
+        match purge::invalidate(cache.as_ref(), &descriptor, &req.target).await {
+            Ok(purged) => info!(
+                "Proxy {} invalidated {:?}: {} entries purged",
+                req.service_name, req.target, purged
+            ),
+            Err(e) => error!("Failed to invalidate {:?} for {}: {}", req.target, req.service_name, e),
+        }
+    }
+}
+
+async fn listen_for_cache_evictions(nats: async_nats::Client, subjects: Subjects, store: Arc<TieredStore>) {
+    let mut sub = match nats.subscribe(subjects.cache_evictions()).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to subscribe to cache evictions: {}", e);
+            return;
+        }
+    };
+
+    info!("Listening for cache evictions");
+
+    while let Some(msg) = futures_util::stream::StreamExt::next(&mut sub).await {
+        match rmp_serde::from_slice::<BusMessage>(&msg.payload) {
+            Ok(BusMessage::CacheEviction(eviction)) => {
+                if let Err(e) = store.apply_eviction(&eviction).await {
+                    warn!("Failed to apply cache eviction: {}", e);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to deserialize cache eviction message: {}", e);
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn evict_stale_proxies(registry: Arc<tokio::sync::RwLock<ProxyRegistry>>, max_missed_heartbeats: u32) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        let evicted = registry.write().await.evict_stale(max_missed_heartbeats);
+        for (service_name, instance_id) in evicted {
+            warn!(
+                "Evicting proxy {} (instance {}): missed {} heartbeats",
+                service_name, instance_id, max_missed_heartbeats
+            );
+        }
+    }
+}
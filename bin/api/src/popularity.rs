@@ -0,0 +1,194 @@
+use crate::store::{CacheStore, StoreResult};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const LAST_ACCESS_KEY: &str = "cache:last_access";
+
+/// Counts cache lookups per key in scored sets of the cache store so refreshes can be spent on popular entries.
+///
+/// Hits go to one sorted set per window (`cache:hits:{window}`) that expires after the following window, so
+/// counts decay without a cleanup job. `cache:last_access` holds the time of the latest lookup of every key.
+/// Lookups are counted in the process and written by [`HitTracker::flush_periodically`], so serving a request never
+/// waits on or spawns store writes.
+#[derive(Debug, Clone)]
+pub struct HitTracker {
+    window: Duration,
+    pending: Arc<Mutex<PendingHits>>,
+}
+
+#[derive(Debug, Default)]
+struct PendingHits {
+    /// Lookups per window and key, so hits counted before a window ends are written to that window.
+    hits: HashMap<(u64, String), u64>,
+    last_access: HashMap<String, u64>,
+}
+
+/// How often counted lookups are written to the store.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl HitTracker {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: window.max(Duration::from_secs(1)),
+            pending: Arc::default(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Counts a lookup of `key`, to be written with the next flush.
+    pub fn record(&self, key: &str) {
+        let now = unix_now();
+        let mut pending = self.pending.lock().unwrap();
+        *pending
+            .hits
+            .entry((now / self.window.as_secs(), key.to_string()))
+            .or_default() += 1;
+        pending.last_access.insert(key.to_string(), now);
+    }
+
+    /// Writes the lookups counted since the last flush to the store, every [`FLUSH_INTERVAL`].
+    pub async fn flush_periodically(self, store: Arc<dyn CacheStore>) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            self.flush(store.as_ref()).await;
+        }
+    }
+
+    /// Writes the lookups counted since the last flush to the store. Writes that fail are dropped, so an unavailable
+    /// store costs some hits rather than holding them in memory.
+    pub async fn flush(&self, store: &dyn CacheStore) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let expiry = self.window * 2;
+
+        let mut failed = 0;
+        let mut last_error = None;
+        for ((window, key), hits) in pending.hits {
+            if let Err(e) = store
+                .incr_score(&self.hits_key(window), &key, hits as f64, expiry)
+                .await
+            {
+                failed += 1;
+                last_error = Some(e);
+            }
+        }
+        for (key, at) in pending.last_access {
+            if let Err(e) = store.set_score(LAST_ACCESS_KEY, &key, at as f64).await {
+                failed += 1;
+                last_error = Some(e);
+            }
+        }
+
+        if let Some(e) = last_error {
+            tracing::debug!("Failed to record {} cache hits: {}", failed, e);
+        }
+    }
+
+    /// Keys with at least `min_hits` lookups in the current and previous window, most popular first, at most
+    /// `limit` of them.
+    pub async fn hot_keys(
+        &self,
+        store: &dyn CacheStore,
+        min_hits: u64,
+        limit: usize,
+    ) -> StoreResult<Vec<(String, u64)>> {
+        let window = unix_now() / self.window.as_secs();
+        let mut hits = store
+            .scores(&[self.hits_key(window), self.hits_key(window.saturating_sub(1))])
+            .await?;
+
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(hits
+            .into_iter()
+            .map(|(key, score)| (key, score as u64))
+            .filter(|(_, hits)| *hits >= min_hits)
+            .take(limit)
+            .collect())
+    }
+
+    /// Time since `key` was last looked up, if it was within the tracked history.
+    pub async fn idle_time(&self, store: &dyn CacheStore, key: &str) -> StoreResult<Option<Duration>> {
+        let last_access = store.score(LAST_ACCESS_KEY, key).await?;
+        Ok(last_access.map(|at| Duration::from_secs(unix_now().saturating_sub(at as u64))))
+    }
+
+    /// Drops last-access records older than two windows.
+    pub async fn prune(&self, store: &dyn CacheStore) -> StoreResult<()> {
+        let cutoff = unix_now().saturating_sub(self.window.as_secs() * 2);
+        store.remove_scores_below(LAST_ACCESS_KEY, cutoff as f64).await
+    }
+
+    fn hits_key(&self, window: u64) -> String {
+        format!("cache:hits:{}", window)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[tokio::test]
+    async fn hits_reach_the_store_when_flushed() {
+        let store = MemoryStore::new(1024 * 1024);
+        let tracker = HitTracker::new(Duration::from_secs(3600));
+
+        tracker.record("proxy:svc:a");
+        tracker.record("proxy:svc:a");
+        tracker.record("proxy:svc:b");
+        assert!(tracker.hot_keys(&store, 1, 10).await.unwrap().is_empty());
+
+        tracker.flush(&store).await;
+        assert_eq!(
+            tracker.hot_keys(&store, 2, 10).await.unwrap(),
+            vec![("proxy:svc:a".to_string(), 2)]
+        );
+        assert!(tracker.idle_time(&store, "proxy:svc:b").await.unwrap().is_some());
+
+        tracker.flush(&store).await;
+        assert_eq!(tracker.hot_keys(&store, 1, 10).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn hits_count_towards_the_window_they_happened_in() {
+        let store = MemoryStore::new(1024 * 1024);
+        let tracker = HitTracker::new(Duration::from_secs(3600));
+        let window = unix_now() / 3600;
+
+        tracker.record("proxy:svc:a");
+        // Hits from the previous window, as if it ended before the flush.
+        tracker
+            .pending
+            .lock()
+            .unwrap()
+            .hits
+            .insert((window - 1, "proxy:svc:a".to_string()), 2);
+        tracker.flush(&store).await;
+
+        assert_eq!(
+            store.score(&tracker.hits_key(window), "proxy:svc:a").await.unwrap(),
+            Some(1.0)
+        );
+        assert_eq!(
+            store.score(&tracker.hits_key(window - 1), "proxy:svc:a").await.unwrap(),
+            Some(2.0)
+        );
+        assert_eq!(
+            tracker.hot_keys(&store, 1, 10).await.unwrap(),
+            vec![("proxy:svc:a".to_string(), 3)]
+        );
+    }
+}
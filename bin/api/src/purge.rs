@@ -0,0 +1,89 @@
+use crate::cache;
+use crate::entry_format;
+use crate::store::{CacheStore, StoreResult};
+use crate::vary;
+use axum::http::HeaderMap;
+use prtl_messages::{InvalidationTarget, ProxyDescriptor};
+use url::Url;
+
+/// Response header in which proxies list the tags of a response, separated by spaces. Stripped before the
+/// response is cached or sent to clients.
+pub const SURROGATE_KEY_HEADER: &str = "surrogate-key";
+
+fn tag_key(service_name: &str, tag: &str) -> String {
+    format!("cache:tag:{}:{}", service_name, tag)
+}
+
+/// Removes the `Surrogate-Key` header and returns the tags it listed.
+pub fn take_tags(headers: &mut HeaderMap) -> Vec<String> {
+    let tags = headers
+        .get_all(SURROGATE_KEY_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(str::split_whitespace)
+        .map(str::to_string)
+        .collect();
+    headers.remove(SURROGATE_KEY_HEADER);
+    tags
+}
+
+/// Indexes listing the entries of each tag, to be listed in when storing an entry.
+pub fn tag_indexes(service_name: &str, tags: &[String]) -> Vec<String> {
+    tags.iter().map(|tag| tag_key(service_name, tag)).collect()
+}
+
+/// Primary cache key hash of a plain `GET` of `url`, as computed for requests without hashed headers.
+pub fn url_hash(desc: &ProxyDescriptor, url: &Url) -> Option<String> {
+    let request = http::Request::get(url.as_str()).body(Vec::<u8>::new()).ok()?;
+    Some(crate::hash::compute_cache_key(&request, desc))
+}
+
+/// Deletes the entry of a URL together with all its `Vary` variants.
+pub async fn purge_url(store: &dyn CacheStore, desc: &ProxyDescriptor, url: &Url) -> StoreResult<u64> {
+    match url_hash(desc, url) {
+        Some(primary) => vary::purge(store, &desc.service_name, &primary).await,
+        None => Ok(0),
+    }
+}
+
+pub async fn purge_tag(store: &dyn CacheStore, service_name: &str, tag: &str) -> StoreResult<u64> {
+    let tag_key = tag_key(service_name, tag);
+    let keys = store.members(&tag_key).await?;
+    store.delete(&[tag_key]).await?;
+
+    let (entries, chunks) = cache::partition_entries(keys);
+    store.delete(&chunks).await?;
+    store.delete(&entries).await
+}
+
+/// Applies an invalidation requested by a proxy and returns the number of deleted entries.
+pub async fn invalidate(
+    store: &dyn CacheStore,
+    desc: &ProxyDescriptor,
+    target: &InvalidationTarget,
+) -> StoreResult<u64> {
+    match target {
+        InvalidationTarget::Url(url) => match Url::parse(url) {
+            Ok(url) => purge_url(store, desc, &url).await,
+            Err(_) => Ok(0),
+        },
+        InvalidationTarget::Tag(tag) => purge_tag(store, &desc.service_name, tag).await,
+        InvalidationTarget::All => purge_service(store, &desc.service_name).await,
+    }
+}
+
+/// Deletes every entry, tag and variant record of a service.
+pub async fn purge_service(store: &dyn CacheStore, service_name: &str) -> StoreResult<u64> {
+    let entries = delete_matching(store, &cache::key(service_name, "")).await?;
+    delete_matching(store, &entry_format::service_chunk_prefix(service_name)).await?;
+    delete_matching(store, &tag_key(service_name, "")).await?;
+    for prefix in vary::service_prefixes(service_name) {
+        delete_matching(store, &prefix).await?;
+    }
+    Ok(entries)
+}
+
+async fn delete_matching(store: &dyn CacheStore, prefix: &str) -> StoreResult<u64> {
+    let keys = store.scan_prefix(prefix).await?;
+    store.delete(&keys).await
+}
@@ -1,22 +1,696 @@
-use prtl_messages::ProxyDescriptor;
-use std::collections::HashMap;
+use crate::domains::{self, DomainIndex};
+use crate::routes::{self, RouteMatch};
+use http::Method;
+use prtl_messages::{PROTOCOL_VERSION, ProxyDescriptor, ProxyInstance, RegisterProxyRequest, Subjects};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug)]
+pub enum RegistrationError {
+    UnsupportedProtocol(u16),
+    EmptyServiceName,
+    NoDomains,
+    EmptyDomain,
+    InvalidDomain(String),
+    EmptyInstanceId,
+    OverlappingDomain { domain: String, service: String },
+    Malformed(String),
+}
+
+impl std::fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistrationError::UnsupportedProtocol(version) => write!(
+                f,
+                "Unsupported protocol version {} (gateway speaks {})",
+                version, PROTOCOL_VERSION
+            ),
+            RegistrationError::EmptyServiceName => write!(f, "Service name must not be empty"),
+            RegistrationError::NoDomains => write!(f, "Descriptor must declare at least one base domain"),
+            RegistrationError::EmptyDomain => write!(f, "Base domains must not be empty"),
+            RegistrationError::InvalidDomain(domain) => write!(f, "Invalid domain pattern: {}", domain),
+            RegistrationError::EmptyInstanceId => write!(f, "Instance id must not be empty"),
+            RegistrationError::OverlappingDomain { domain, service } => {
+                write!(f, "Domain {} is already served by {}", domain, service)
+            }
+            RegistrationError::Malformed(error) => write!(f, "Malformed registration: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RegistrationError {}
+
+impl RegistrationError {
+    /// Explains why a registration that couldn't be decoded is rejected. Proxies speaking another protocol
+    /// version usually send descriptors the gateway can't decode, but the version itself still can be.
+    pub fn undecodable(payload: &[u8], error: impl std::fmt::Display) -> Self {
+        /// The part of a registration every protocol version agrees on.
+        #[derive(serde::Deserialize)]
+        enum Probe {
+            RegisterParser {
+                #[serde(default)]
+                protocol_version: u16,
+            },
+        }
+
+        match rmp_serde::from_slice(payload) {
+            Ok(Probe::RegisterParser { protocol_version }) if protocol_version != PROTOCOL_VERSION => {
+                RegistrationError::UnsupportedProtocol(protocol_version)
+            }
+            _ => RegistrationError::Malformed(error.to_string()),
+        }
+    }
+}
+
+/// Why no proxy could be selected for a request.
+#[derive(Debug)]
+pub enum RouteError {
+    /// No proxy serves the domain at all.
+    NoProxy,
+    /// The domain is served, but not on the requested port.
+    PortNotAllowed(u16),
+    /// The domain is served, but none of its proxies declares a route for the path.
+    NoRoute,
+    /// A route matches the path, but not the method.
+    MethodNotAllowed(Vec<Method>),
+}
+
+/// How the gateway spreads RPCs over the live instances of a service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadBalancing {
+    /// Publish on the shared service subject and let the NATS queue group pick an instance.
+    #[default]
+    QueueGroup,
+    /// Cycle through instances using their per-instance subjects.
+    RoundRobin,
+    /// Pick the instance with the fewest requests in flight relative to its advertised concurrency.
+    LeastInFlight,
+}
+
+impl std::str::FromStr for LoadBalancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue-group" => Ok(LoadBalancing::QueueGroup),
+            "round-robin" => Ok(LoadBalancing::RoundRobin),
+            "least-in-flight" => Ok(LoadBalancing::LeastInFlight),
+            other => Err(format!("Unknown load balancing strategy: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RegisteredInstance {
+    info: ProxyInstance,
+    heartbeat_interval: Duration,
+    last_seen: Instant,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl RegisteredInstance {
+    /// In-flight requests per advertised slot, scaled to avoid floats.
+    fn load(&self) -> usize {
+        let capacity = self.info.max_concurrency.unwrap_or(1).max(1) as usize;
+        self.in_flight.load(Ordering::Relaxed) * 1000 / capacity
+    }
+}
+
+#[derive(Debug)]
+struct RegisteredService {
+    descriptor: ProxyDescriptor,
+    /// Start time of the newest instance seen, whose descriptor is the one in use.
+    descriptor_started_at: SystemTime,
+    instances: BTreeMap<String, RegisteredInstance>,
+    next_instance: AtomicUsize,
+}
+
+/// Where to send an RPC. Keeps the chosen instance's in-flight counter raised until dropped.
+#[derive(Debug)]
+pub struct RpcTarget {
+    pub subject: String,
+    _in_flight: Option<InFlightGuard>,
+}
+
+#[derive(Debug)]
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlightGuard {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct ProxyRegistry {
-    proxies: HashMap<String, ProxyDescriptor>,
+    services: HashMap<String, RegisteredService>,
+    domains: DomainIndex,
+    load_balancing: LoadBalancing,
+    subjects: Subjects,
 }
 
 impl ProxyRegistry {
-    pub fn register(&mut self, descriptor: ProxyDescriptor) {
-        tracing::info!("Registering proxy: {}", descriptor.service_name);
-        self.proxies.insert(descriptor.service_name.clone(), descriptor);
+    pub fn new(load_balancing: LoadBalancing, subjects: Subjects) -> Self {
+        Self {
+            services: HashMap::new(),
+            domains: DomainIndex::default(),
+            load_balancing,
+            subjects,
+        }
+    }
+
+    pub fn register(&mut self, request: RegisterProxyRequest) -> Result<(), RegistrationError> {
+        self.validate(&request)?;
+
+        let descriptor = request.descriptor;
+        let instance = request.instance;
+        tracing::info!(
+            "Registering proxy: {} (instance {} on {}, max concurrency {:?})",
+            descriptor.service_name,
+            instance.instance_id,
+            instance.host,
+            instance.max_concurrency
+        );
+
+        let service = match self.services.entry(descriptor.service_name.clone()) {
+            Entry::Occupied(entry) => {
+                let service = entry.into_mut();
+                if instance.started_at >= service.descriptor_started_at {
+                    service.descriptor_started_at = instance.started_at;
+                    if service.descriptor != descriptor {
+                        tracing::info!(
+                            "Instance {} replaces the descriptor of {}",
+                            instance.instance_id,
+                            descriptor.service_name
+                        );
+                        self.domains.remove_service(&descriptor.service_name);
+                        for pattern in &descriptor.base_domains {
+                            self.domains.insert(pattern, &descriptor.service_name);
+                        }
+                        service.descriptor = descriptor;
+                    }
+                } else if service.descriptor != descriptor {
+                    tracing::warn!(
+                        "Instance {} of {} runs an older descriptor, keeping the current one",
+                        instance.instance_id,
+                        descriptor.service_name
+                    );
+                }
+                service
+            }
+            Entry::Vacant(entry) => {
+                for pattern in &descriptor.base_domains {
+                    self.domains.insert(pattern, &descriptor.service_name);
+                }
+
+                entry.insert(RegisteredService {
+                    descriptor,
+                    descriptor_started_at: instance.started_at,
+                    instances: BTreeMap::new(),
+                    next_instance: AtomicUsize::new(0),
+                })
+            }
+        };
+
+        // Keep the in-flight counter when an instance re-registers after discovery
+        let in_flight = service
+            .instances
+            .get(&instance.instance_id)
+            .map(|existing| existing.in_flight.clone())
+            .unwrap_or_default();
+
+        service.instances.insert(
+            instance.instance_id.clone(),
+            RegisteredInstance {
+                info: instance,
+                heartbeat_interval: request.heartbeat_interval,
+                last_seen: Instant::now(),
+                in_flight,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Removes an instance that is shutting down. Returns `false` if the instance was not registered.
+    pub fn deregister(&mut self, service_name: &str, instance_id: &str) -> bool {
+        let Some(service) = self.services.get_mut(service_name) else {
+            return false;
+        };
+
+        if service.instances.remove(instance_id).is_none() {
+            return false;
+        }
+
+        tracing::info!("Deregistering proxy: {} (instance {})", service_name, instance_id);
+
+        if service.instances.is_empty() {
+            tracing::info!("No instances of {} left, removing service", service_name);
+            self.services.remove(service_name);
+            self.domains.remove_service(service_name);
+        }
+
+        true
     }
 
-    pub fn find_proxy_for_domain(&self, domain: &str) -> Option<&ProxyDescriptor> {
-        self.proxies.values().find(|desc| {
-            desc.base_domains
+    /// Records a heartbeat. Returns `false` if the instance is not registered (e.g. it was evicted or the
+    /// gateway restarted), in which case the proxy needs to register again.
+    pub fn heartbeat(&mut self, service_name: &str, instance_id: &str) -> bool {
+        match self
+            .services
+            .get_mut(service_name)
+            .and_then(|service| service.instances.get_mut(instance_id))
+        {
+            Some(instance) => {
+                instance.last_seen = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes instances that missed more than `max_missed_heartbeats` consecutive beats and returns their
+    /// `(service, instance)` pairs. Services without any instance left are dropped entirely.
+    pub fn evict_stale(&mut self, max_missed_heartbeats: u32) -> Vec<(String, String)> {
+        let now = Instant::now();
+        let mut evicted = Vec::new();
+        let mut removed = Vec::new();
+
+        self.services.retain(|name, service| {
+            service.instances.retain(|instance_id, instance| {
+                let deadline = instance.heartbeat_interval * max_missed_heartbeats;
+                let alive = now.duration_since(instance.last_seen) <= deadline;
+                if !alive {
+                    evicted.push((name.clone(), instance_id.clone()));
+                }
+                alive
+            });
+
+            if service.instances.is_empty() {
+                removed.push(name.clone());
+            }
+            !service.instances.is_empty()
+        });
+
+        for name in removed {
+            self.domains.remove_service(&name);
+        }
+
+        evicted
+    }
+
+    /// Finds the proxy responsible for an upstream request. The most specific domain match wins (exact before
+    /// longer suffixes before shorter ones); among equally specific domains the most specific route wins.
+    /// Proxies whose ports or routes exclude the request are skipped in favour of less specific domains.
+    pub fn find_proxy(
+        &self,
+        host: &str,
+        explicit_port: Option<u16>,
+        method: &Method,
+        path: &str,
+    ) -> Result<&ProxyDescriptor, RouteError> {
+        let mut error = RouteError::NoProxy;
+
+        for candidates in self.domains.lookup(host) {
+            let mut best: Option<(usize, &ProxyDescriptor)> = None;
+
+            for desc in candidates
                 .iter()
-                .any(|base_domain| domain == base_domain || domain.ends_with(&format!(".{}", base_domain)))
+                .filter_map(|name| self.services.get(name))
+                .map(|service| &service.descriptor)
+            {
+                let port = desc.upstream.effective_port(explicit_port);
+                let port_allowed = (explicit_port.is_none() || desc.upstream.allow_explicit_port)
+                    && (desc.ports.is_empty() || desc.ports.contains(&port));
+                if !port_allowed {
+                    if matches!(error, RouteError::NoProxy | RouteError::NoRoute) {
+                        error = RouteError::PortNotAllowed(port);
+                    }
+                    continue;
+                }
+
+                match routes::match_routes(desc, method, path) {
+                    RouteMatch::Matched { specificity } => {
+                        // Registration rejects ties; should one slip through, the same service still wins on
+                        // every gateway.
+                        if best.is_none_or(|(best_specificity, best_desc)| {
+                            specificity > best_specificity
+                                || (specificity == best_specificity && desc.service_name < best_desc.service_name)
+                        }) {
+                            best = Some((specificity, desc));
+                        }
+                    }
+                    RouteMatch::MethodNotAllowed { allowed } => match &mut error {
+                        RouteError::MethodNotAllowed(methods) => methods.extend(allowed),
+                        _ => error = RouteError::MethodNotAllowed(allowed),
+                    },
+                    RouteMatch::NotMatched => {
+                        if matches!(error, RouteError::NoProxy) {
+                            error = RouteError::NoRoute;
+                        }
+                    }
+                }
+            }
+
+            if let Some((_, desc)) = best {
+                return Ok(desc);
+            }
+        }
+
+        Err(error)
+    }
+
+    pub fn descriptor(&self, service_name: &str) -> Option<&ProxyDescriptor> {
+        self.services.get(service_name).map(|service| &service.descriptor)
+    }
+
+    pub fn descriptors(&self) -> impl Iterator<Item = &ProxyDescriptor> {
+        self.services.values().map(|service| &service.descriptor)
+    }
+
+    /// Picks the subject an RPC for `service_name` should be sent to according to the balancing strategy.
+    pub fn select_target(&self, service_name: &str) -> Option<RpcTarget> {
+        let service = self.services.get(service_name)?;
+
+        let instance = match self.load_balancing {
+            LoadBalancing::QueueGroup => {
+                return Some(RpcTarget {
+                    subject: self.subjects.rpc(service_name),
+                    _in_flight: None,
+                });
+            }
+            LoadBalancing::RoundRobin => {
+                let index = service.next_instance.fetch_add(1, Ordering::Relaxed) % service.instances.len();
+                service.instances.values().nth(index)?
+            }
+            LoadBalancing::LeastInFlight => service.instances.values().min_by_key(|instance| instance.load())?,
+        };
+
+        Some(RpcTarget {
+            subject: self.subjects.instance_rpc(service_name, &instance.info.instance_id),
+            _in_flight: Some(InFlightGuard::new(&instance.in_flight)),
         })
     }
+
+    fn validate(&self, request: &RegisterProxyRequest) -> Result<(), RegistrationError> {
+        let descriptor = &request.descriptor;
+
+        if request.protocol_version != PROTOCOL_VERSION {
+            return Err(RegistrationError::UnsupportedProtocol(request.protocol_version));
+        }
+
+        if descriptor.service_name.is_empty() {
+            return Err(RegistrationError::EmptyServiceName);
+        }
+
+        if descriptor.base_domains.is_empty() {
+            return Err(RegistrationError::NoDomains);
+        }
+
+        if descriptor.base_domains.iter().any(|d| d.is_empty()) {
+            return Err(RegistrationError::EmptyDomain);
+        }
+
+        if let Some(domain) = descriptor.base_domains.iter().find(|d| !domains::is_valid_pattern(d)) {
+            return Err(RegistrationError::InvalidDomain(domain.clone()));
+        }
+
+        if request.instance.instance_id.is_empty() {
+            return Err(RegistrationError::EmptyInstanceId);
+        }
+
+        // Instances started after the one whose descriptor is in use replace it, so a rolling deploy switches to
+        // the new descriptor with its first instance. Older instances join with whatever is in use.
+        if self
+            .services
+            .get(&descriptor.service_name)
+            .is_some_and(|service| request.instance.started_at < service.descriptor_started_at)
+        {
+            return Ok(());
+        }
+
+        for other in self.services.values().map(|service| &service.descriptor) {
+            if other.service_name == descriptor.service_name {
+                continue;
+            }
+
+            // Identical patterns, or a domain and the wildcard over it, are only ambiguous if the ports and
+            // routes can't tell them apart
+            let ports_overlap = descriptor.ports.is_empty()
+                || other.ports.is_empty()
+                || descriptor.ports.iter().any(|p| other.ports.contains(p));
+            if !ports_overlap || !routes::routes_overlap(&descriptor.routes, &other.routes) {
+                continue;
+            }
+
+            if let Some(domain) = descriptor
+                .base_domains
+                .iter()
+                .find(|d| other.base_domains.iter().any(|o| domains::patterns_overlap(o, d)))
+            {
+                return Err(RegistrationError::OverlappingDomain {
+                    domain: domain.clone(),
+                    service: other.service_name.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prtl_messages::BusMessage;
+    use std::time::SystemTime;
+
+    fn request(service: &str, domains: &[&str]) -> RegisterProxyRequest {
+        RegisterProxyRequest {
+            protocol_version: PROTOCOL_VERSION,
+            instance: ProxyInstance {
+                instance_id: format!("{}-1", service),
+                host: "localhost".into(),
+                started_at: SystemTime::now(),
+                max_concurrency: None,
+            },
+            heartbeat_interval: Duration::from_secs(5),
+            descriptor: ProxyDescriptor {
+                service_name: service.into(),
+                base_domains: domains.iter().map(|d| d.to_string()).collect(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn instance(mut request: RegisterProxyRequest, instance_id: &str, started_at: SystemTime) -> RegisterProxyRequest {
+        request.instance.instance_id = instance_id.into();
+        request.instance.started_at = started_at;
+        request
+    }
+
+    fn service_for(registry: &ProxyRegistry, host: &str) -> Option<String> {
+        registry
+            .find_proxy(host, None, &Method::GET, "/")
+            .ok()
+            .map(|desc| desc.service_name.clone())
+    }
+
+    #[test]
+    fn rejects_domain_claimed_by_wildcard_of_another_service() {
+        for (first, second) in [("example.com", "*.example.com"), ("*.example.com", "example.com")] {
+            let mut registry = ProxyRegistry::default();
+            registry.register(request("a", &[first])).unwrap();
+
+            let error = registry.register(request("b", &[second])).unwrap_err();
+            assert!(matches!(error, RegistrationError::OverlappingDomain { service, .. } if service == "a"));
+            assert_eq!(service_for(&registry, "www.example.com").as_deref(), Some("a"));
+        }
+    }
+
+    #[test]
+    fn more_specific_domains_win() {
+        let mut registry = ProxyRegistry::default();
+        registry.register(request("a", &["example.com"])).unwrap();
+        registry.register(request("b", &["*.api.example.com"])).unwrap();
+        registry.register(request("c", &["v1.api.example.com"])).unwrap();
+
+        assert_eq!(service_for(&registry, "example.com").as_deref(), Some("a"));
+        assert_eq!(service_for(&registry, "www.example.com").as_deref(), Some("a"));
+        assert_eq!(service_for(&registry, "api.example.com").as_deref(), Some("a"));
+        assert_eq!(service_for(&registry, "v1.api.example.com").as_deref(), Some("c"));
+        assert_eq!(service_for(&registry, "v2.api.example.com").as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn undecodable_registrations_report_their_protocol_version() {
+        #[derive(serde::Serialize)]
+        struct OldRequest {
+            protocol_version: u16,
+            descriptor: String,
+        }
+        #[derive(serde::Serialize)]
+        enum OldMessage {
+            RegisterParser(OldRequest),
+        }
+
+        let payload = rmp_serde::to_vec_named(&OldMessage::RegisterParser(OldRequest {
+            protocol_version: PROTOCOL_VERSION + 1,
+            descriptor: "cdnlibs".into(),
+        }))
+        .unwrap();
+        let error = rmp_serde::from_slice::<BusMessage>(&payload).unwrap_err();
+        assert!(matches!(
+            RegistrationError::undecodable(&payload, error),
+            RegistrationError::UnsupportedProtocol(version) if version == PROTOCOL_VERSION + 1
+        ));
+
+        assert!(matches!(
+            RegistrationError::undecodable(b"garbage", "invalid"),
+            RegistrationError::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_identical_domains() {
+        let mut registry = ProxyRegistry::default();
+        registry.register(request("a", &["example.com"])).unwrap();
+        let error = registry.register(request("b", &["EXAMPLE.com"])).unwrap_err();
+        assert!(matches!(error, RegistrationError::OverlappingDomain { .. }));
+    }
+
+    #[test]
+    fn newer_instances_replace_the_descriptor() {
+        let started = SystemTime::now();
+        let later = started + Duration::from_secs(60);
+
+        let mut registry = ProxyRegistry::default();
+        registry
+            .register(instance(request("a", &["old.example.com"]), "a-1", started))
+            .unwrap();
+        registry
+            .register(instance(request("a", &["new.example.com"]), "a-2", later))
+            .unwrap();
+        assert_eq!(service_for(&registry, "new.example.com").as_deref(), Some("a"));
+        assert_eq!(service_for(&registry, "old.example.com"), None);
+
+        // The instance still running the old version re-registers after discovery, without switching back.
+        registry
+            .register(instance(request("a", &["old.example.com"]), "a-1", started))
+            .unwrap();
+        assert_eq!(service_for(&registry, "new.example.com").as_deref(), Some("a"));
+        assert_eq!(service_for(&registry, "old.example.com"), None);
+        assert_eq!(registry.services["a"].instances.len(), 2);
+    }
+
+    #[test]
+    fn evicts_instances_after_missed_heartbeats() {
+        let mut registry = ProxyRegistry::default();
+        for id in ["a-1", "a-2"] {
+            let mut request = instance(request("a", &["example.com"]), id, SystemTime::now());
+            request.heartbeat_interval = Duration::from_millis(50);
+            registry.register(request).unwrap();
+        }
+        assert!(registry.evict_stale(2).is_empty());
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(registry.heartbeat("a", "a-1"));
+        assert_eq!(registry.evict_stale(2), [("a".to_string(), "a-2".to_string())]);
+        assert!(!registry.heartbeat("a", "a-2"));
+        assert_eq!(service_for(&registry, "example.com").as_deref(), Some("a"));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(registry.evict_stale(2), [("a".to_string(), "a-1".to_string())]);
+        assert!(registry.descriptor("a").is_none());
+        assert_eq!(service_for(&registry, "example.com"), None);
+    }
+
+    #[test]
+    fn deregistering_the_last_instance_removes_the_service() {
+        let mut registry = ProxyRegistry::default();
+        registry
+            .register(instance(request("a", &["example.com"]), "a-1", SystemTime::now()))
+            .unwrap();
+        registry
+            .register(instance(request("a", &["example.com"]), "a-2", SystemTime::now()))
+            .unwrap();
+
+        assert!(registry.deregister("a", "a-1"));
+        assert!(!registry.deregister("a", "a-1"));
+        assert_eq!(service_for(&registry, "example.com").as_deref(), Some("a"));
+
+        assert!(registry.deregister("a", "a-2"));
+        assert!(registry.descriptor("a").is_none());
+        assert_eq!(service_for(&registry, "example.com"), None);
+        assert!(!registry.deregister("a", "a-2"));
+
+        // The domains are free for another service again.
+        registry.register(request("b", &["example.com"])).unwrap();
+        assert_eq!(service_for(&registry, "example.com").as_deref(), Some("b"));
+    }
+
+    fn balanced(load_balancing: LoadBalancing, instances: &[(&str, Option<u32>)]) -> ProxyRegistry {
+        let mut registry = ProxyRegistry::new(load_balancing, Subjects::default());
+        for (id, max_concurrency) in instances {
+            let mut request = instance(request("a", &["example.com"]), id, SystemTime::now());
+            request.instance.max_concurrency = *max_concurrency;
+            registry.register(request).unwrap();
+        }
+        registry
+    }
+
+    #[test]
+    fn queue_group_uses_the_service_subject() {
+        let registry = balanced(LoadBalancing::QueueGroup, &[("a-1", None), ("a-2", None)]);
+        let target = registry.select_target("a").unwrap();
+        assert_eq!(target.subject, Subjects::default().rpc("a"));
+        assert!(registry.select_target("b").is_none());
+    }
+
+    #[test]
+    fn round_robin_cycles_through_instances() {
+        let registry = balanced(
+            LoadBalancing::RoundRobin,
+            &[("a-1", None), ("a-2", None), ("a-3", None)],
+        );
+        let subjects: Vec<String> = (0..6).map(|_| registry.select_target("a").unwrap().subject).collect();
+
+        let instance = |id| Subjects::default().instance_rpc("a", id);
+        let cycle = [instance("a-1"), instance("a-2"), instance("a-3")];
+        assert_eq!(subjects[..3], cycle);
+        assert_eq!(subjects[3..], cycle);
+    }
+
+    #[test]
+    fn least_in_flight_picks_the_idlest_instance() {
+        let registry = balanced(LoadBalancing::LeastInFlight, &[("a-1", Some(1)), ("a-2", Some(4))]);
+        let instance = |id| Subjects::default().instance_rpc("a", id);
+
+        let first = registry.select_target("a").unwrap();
+        assert_eq!(first.subject, instance("a-1"));
+        // a-1 is busy, a-2 has room for four requests before it is as loaded.
+        let held: Vec<RpcTarget> = (0..3).map(|_| registry.select_target("a").unwrap()).collect();
+        assert!(held.iter().all(|target| target.subject == instance("a-2")));
+
+        // Dropping a target releases its slot.
+        drop(first);
+        assert_eq!(registry.select_target("a").unwrap().subject, instance("a-1"));
+        drop(held);
+        let in_flight: Vec<usize> = registry.services["a"]
+            .instances
+            .values()
+            .map(|instance| instance.in_flight.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(in_flight, [0, 0]);
+    }
 }
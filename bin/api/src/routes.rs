@@ -0,0 +1,292 @@
+use http::Method;
+use prtl_messages::{ProxyDescriptor, RoutePattern};
+
+/// Outcome of matching a request against the routes of one proxy.
+#[derive(Debug)]
+pub enum RouteMatch {
+    /// The request is accepted; higher specificity wins over other proxies.
+    Matched {
+        specificity: usize,
+    },
+    /// A route matches the path but not the method.
+    MethodNotAllowed {
+        allowed: Vec<Method>,
+    },
+    NotMatched,
+}
+
+/// Matches `method` and `path` against the routes declared by `desc`. A proxy without routes accepts every
+/// request with the lowest specificity.
+pub fn match_routes(desc: &ProxyDescriptor, method: &Method, path: &str) -> RouteMatch {
+    if desc.routes.is_empty() {
+        return RouteMatch::Matched { specificity: 0 };
+    }
+
+    let mut best = None;
+    let mut allowed = Vec::new();
+
+    for route in &desc.routes {
+        let Some(specificity) = path_specificity(route, path) else {
+            continue;
+        };
+
+        if route.methods.is_empty() || route.methods.contains(method) {
+            best = best.max(Some(specificity));
+        } else {
+            allowed.extend(route.methods.iter().cloned());
+        }
+    }
+
+    match best {
+        Some(specificity) => RouteMatch::Matched { specificity },
+        None if !allowed.is_empty() => RouteMatch::MethodNotAllowed { allowed },
+        None => RouteMatch::NotMatched,
+    }
+}
+
+/// Checks whether two route sets can claim the same request with the same specificity, so neither would win it.
+pub fn routes_overlap(a: &[RoutePattern], b: &[RoutePattern]) -> bool {
+    if a.is_empty() || b.is_empty() {
+        return true;
+    }
+
+    a.iter().any(|ra| {
+        b.iter().any(|rb| {
+            let methods_overlap =
+                ra.methods.is_empty() || rb.methods.is_empty() || ra.methods.iter().any(|m| rb.methods.contains(m));
+            methods_overlap && specificity(&ra.path) == specificity(&rb.path) && paths_intersect(&ra.path, &rb.path)
+        })
+    })
+}
+
+/// Returns the number of literal characters of the route's path pattern if it matches `path`.
+///
+/// Patterns without wildcards are path prefixes ending at a segment boundary, so `/api` matches `/api` and
+/// `/api/users` but not `/apiary`. Otherwise the whole path must match, where `*` stands for
+/// anything within a single segment and `**` for anything across segments.
+fn path_specificity(route: &RoutePattern, path: &str) -> Option<usize> {
+    let pattern = route.path.as_str();
+
+    let matched = if pattern.contains('*') {
+        glob_matches(pattern, path)
+    } else {
+        prefix_matches(pattern, path)
+    };
+
+    matched.then(|| specificity(pattern))
+}
+
+fn specificity(pattern: &str) -> usize {
+    pattern.chars().filter(|c| *c != '*').count()
+}
+
+fn prefix_matches(pattern: &str, path: &str) -> bool {
+    match path.strip_prefix(pattern) {
+        Some(rest) => rest.is_empty() || pattern.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let path: Vec<Token> = path.bytes().map(Token::Byte).collect();
+    intersect(&tokens(pattern), &path)
+}
+
+/// Whether some path matches both patterns.
+fn paths_intersect(a: &str, b: &str) -> bool {
+    let (a, b) = (globs(a), globs(b));
+    a.iter().any(|a| b.iter().any(|b| intersect(a, b)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Byte(u8),
+    /// `*`, any bytes but `/`.
+    Star,
+    /// `**`, any bytes.
+    DoubleStar,
+}
+
+fn tokens(pattern: &str) -> Vec<Token> {
+    let mut tokens = Vec::with_capacity(pattern.len());
+    let mut bytes = pattern.bytes().peekable();
+    while let Some(byte) = bytes.next() {
+        tokens.push(match byte {
+            b'*' if bytes.next_if_eq(&b'*').is_some() => Token::DoubleStar,
+            b'*' => Token::Star,
+            byte => Token::Byte(byte),
+        });
+    }
+    tokens
+}
+
+/// The globs matching the same paths as a pattern: the pattern itself, or for a prefix the prefix alone and
+/// followed by a segment boundary.
+fn globs(pattern: &str) -> Vec<Vec<Token>> {
+    let tokens = tokens(pattern);
+    if pattern.contains('*') {
+        return vec![tokens];
+    }
+
+    let mut nested = tokens.clone();
+    if !pattern.ends_with('/') {
+        nested.push(Token::Byte(b'/'));
+    }
+    nested.push(Token::DoubleStar);
+    match pattern.ends_with('/') {
+        true => vec![nested],
+        false => vec![tokens, nested],
+    }
+}
+
+/// Whether some input matches both token sequences, walking pairs of positions in `a` and `b` so each pair is
+/// visited once, however many wildcards there are.
+fn intersect(a: &[Token], b: &[Token]) -> bool {
+    let mut visited = vec![false; (a.len() + 1) * (b.len() + 1)];
+    let mut stack = vec![(0, 0)];
+
+    while let Some((i, j)) = stack.pop() {
+        let state = &mut visited[i * (b.len() + 1) + j];
+        if *state {
+            continue;
+        }
+        *state = true;
+
+        let (ta, tb) = (a.get(i), b.get(j));
+        if ta.is_none() && tb.is_none() {
+            return true;
+        }
+
+        // Wildcards may match nothing.
+        if matches!(ta, Some(Token::Star | Token::DoubleStar)) {
+            stack.push((i + 1, j));
+        }
+        if matches!(tb, Some(Token::Star | Token::DoubleStar)) {
+            stack.push((i, j + 1));
+        }
+
+        // Consume a byte both accept. Two wildcards consuming together gets nowhere new.
+        match (ta, tb) {
+            (Some(Token::Byte(x)), Some(Token::Byte(y))) if x == y => stack.push((i + 1, j + 1)),
+            (Some(Token::Byte(x)), Some(wildcard)) if accepts(*wildcard, *x) => stack.push((i + 1, j)),
+            (Some(wildcard), Some(Token::Byte(y))) if accepts(*wildcard, *y) => stack.push((i, j + 1)),
+            _ => {}
+        }
+    }
+    false
+}
+
+fn accepts(wildcard: Token, byte: u8) -> bool {
+    match wildcard {
+        Token::Star => byte != b'/',
+        Token::DoubleStar => true,
+        Token::Byte(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, methods: &[Method]) -> RoutePattern {
+        RoutePattern {
+            path: path.into(),
+            methods: methods.to_vec(),
+        }
+    }
+
+    fn descriptor(routes: Vec<RoutePattern>) -> ProxyDescriptor {
+        ProxyDescriptor {
+            service_name: "test".into(),
+            routes,
+            ..Default::default()
+        }
+    }
+
+    fn specificity(path: &str, pattern: &str) -> Option<usize> {
+        path_specificity(&route(pattern, &[]), path)
+    }
+
+    #[test]
+    fn prefixes_end_at_segment_boundaries() {
+        assert_eq!(specificity("/api", "/api"), Some(4));
+        assert_eq!(specificity("/api/users", "/api"), Some(4));
+        assert_eq!(specificity("/api/users", "/api/"), Some(5));
+        assert_eq!(specificity("/apiary", "/api"), None);
+        assert_eq!(specificity("/api", "/api/"), None);
+        assert_eq!(specificity("/anything", "/"), Some(1));
+    }
+
+    #[test]
+    fn single_star_stays_within_a_segment() {
+        assert_eq!(specificity("/api/v1/items", "/api/*/items"), Some(11));
+        assert_eq!(specificity("/api/v1/v2/items", "/api/*/items"), None);
+        assert_eq!(specificity("/api/v1/items/42", "/api/*/items"), None);
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert_eq!(specificity("/api/v1/v2/items", "/api/**/items"), Some(11));
+        assert_eq!(specificity("/api/items/1/2", "/api/items/**"), Some(11));
+        assert_eq!(specificity("/other/items", "/api/**"), None);
+    }
+
+    #[test]
+    fn most_specific_route_wins() {
+        let desc = descriptor(vec![route("/", &[]), route("/api/users", &[])]);
+        assert!(matches!(
+            match_routes(&desc, &Method::GET, "/api/users/1"),
+            RouteMatch::Matched { specificity: 10 }
+        ));
+        assert!(matches!(
+            match_routes(&desc, &Method::GET, "/api/usersettings"),
+            RouteMatch::Matched { specificity: 1 }
+        ));
+    }
+
+    #[test]
+    fn reports_allowed_methods() {
+        let desc = descriptor(vec![route("/api", &[Method::POST])]);
+        match match_routes(&desc, &Method::GET, "/api/items") {
+            RouteMatch::MethodNotAllowed { allowed } => assert_eq!(allowed, vec![Method::POST]),
+            other => panic!("unexpected match: {:?}", other),
+        }
+        assert!(matches!(
+            match_routes(&desc, &Method::GET, "/apiary"),
+            RouteMatch::NotMatched
+        ));
+    }
+
+    #[test]
+    fn many_double_stars_match_quickly() {
+        let pattern = "/**a".repeat(30);
+        let path = "/a".repeat(29) + "/b";
+        assert_eq!(specificity(&path, &pattern), None);
+    }
+
+    #[test]
+    fn routes_overlap_when_a_path_ties() {
+        let overlap = |a: &str, b: &str| routes_overlap(&[route(a, &[])], &[route(b, &[])]);
+
+        assert!(overlap("/api", "/api"));
+        assert!(overlap("/api/*/v1", "/api/v1/**"));
+        assert!(overlap("/**/users", "/api/ab"));
+        assert!(overlap("/a*", "/*b"));
+        // Different specificities: the more specific route always wins.
+        assert!(!overlap("/api", "/api/users"));
+        assert!(!overlap("/api/**", "/api/*/items"));
+        // Equal specificities but no common path.
+        assert!(!overlap("/api", "/web"));
+        assert!(!overlap("/api/*", "/web/**"));
+        assert!(!overlap("/a*/x", "/b*/x"));
+        assert!(!overlap("/api/*", "/api"));
+    }
+
+    #[test]
+    fn routes_overlap_only_on_shared_methods() {
+        let get = [route("/api", &[Method::GET])];
+        assert!(!routes_overlap(&get, &[route("/api", &[Method::POST])]));
+        assert!(routes_overlap(&get, &[route("/api", &[])]));
+        assert!(routes_overlap(&get, &[]));
+    }
+}
@@ -1,9 +1,39 @@
+use crate::coalesce::SingleFlight;
+use crate::entry_format::EntryFormat;
+use crate::handlers::SharedResponse;
+use crate::popularity::HitTracker;
 use crate::registry::ProxyRegistry;
+use crate::store::{CacheStore, TierStats};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct AppState {
     pub nats: async_nats::Client,
-    pub redis: redis::aio::ConnectionManager,
+    pub cache: Arc<dyn CacheStore>,
+    /// Lookups per cache tier, when an L1 runs in front of Redis.
+    pub tier_stats: Option<Arc<TierStats>>,
+    /// How cache entries are compressed and chunked when stored.
+    pub entry_format: EntryFormat,
     pub proxy_registry: Arc<tokio::sync::RwLock<ProxyRegistry>>,
+    pub request_timeouts: RequestTimeouts,
+    /// Proxy calls in progress, keyed by cache key, so concurrent misses share one call.
+    pub in_flight: Arc<SingleFlight<SharedResponse>>,
+    /// Also coalesce across gateways with a lock in the cache store.
+    pub distributed_lock: bool,
+    pub hit_tracker: HitTracker,
+}
+
+/// Bounds for how long the gateway waits on a proxy. `default` applies when neither the proxy nor the client
+/// asks for a timeout; nobody can exceed `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestTimeouts {
+    pub default: Duration,
+    pub max: Duration,
+}
+
+impl RequestTimeouts {
+    pub fn resolve(&self, requested: Option<Duration>) -> Duration {
+        requested.unwrap_or(self.default).min(self.max)
+    }
 }
@@ -0,0 +1,114 @@
+//! Storage behind the response cache and its bookkeeping: cache entries, the `Vary` and tag indexes, hit
+//! counters and coalescing locks.
+//!
+//! [`RedisStore`] shares everything between gateways through Redis/DragonflyDB, [`MemoryStore`] keeps it in the
+//! process for development and CI. `CACHE_BACKEND` picks one, see [`CacheBackend`]. [`TieredStore`] puts a small
+//! in-process tier in front of Redis.
+
+mod memory;
+mod redis;
+mod tiered;
+
+pub use memory::MemoryStore;
+pub use redis::RedisStore;
+pub use tiered::{TierStats, TieredStore};
+
+use std::time::Duration;
+
+/// Which [`CacheStore`] the gateway runs with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheBackend {
+    #[default]
+    Redis,
+    /// In-process and bounded by `CACHE_MEMORY_MAX_BYTES`. Nothing is shared with other gateways.
+    Memory,
+}
+
+impl std::str::FromStr for CacheBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redis" => Ok(CacheBackend::Redis),
+            "memory" => Ok(CacheBackend::Memory),
+            other => Err(format!("Unknown cache backend: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StoreError(String);
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<::redis::RedisError> for StoreError {
+    fn from(e: ::redis::RedisError) -> Self {
+        Self(e.to_string())
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// What a value is stored with besides its TTL.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Indexes the key is added to, e.g. the variants of a URL or the entries of a tag. Storing a value only ever
+    /// extends an index's expiry, so indexes live at least as long as the values in them.
+    pub indexes: Vec<String>,
+}
+
+/// Key-value storage with expiry, indexes and scored sets.
+///
+/// Keys of values, indexes and scored sets share one namespace: [`CacheStore::delete`], [`CacheStore::ttl`] and
+/// [`CacheStore::scan_prefix`] apply to all of them.
+#[async_trait::async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>>;
+
+    /// [`CacheStore::get`] along with the time until `key` expires, see [`CacheStore::ttl`].
+    async fn get_with_ttl(&self, key: &str) -> StoreResult<Option<(Vec<u8>, Option<Duration>)>> {
+        let Some(value) = self.get(key).await? else {
+            return Ok(None);
+        };
+        Ok(Some((value, self.ttl(key).await?)))
+    }
+
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Duration, meta: &Metadata) -> StoreResult<()>;
+
+    /// Stores `value` unless `key` exists. Returns whether it was stored.
+    async fn put_if_absent(&self, key: &str, value: Vec<u8>, ttl: Duration) -> StoreResult<bool>;
+
+    /// Deletes `keys` and returns how many existed.
+    async fn delete(&self, keys: &[String]) -> StoreResult<u64>;
+
+    /// Deletes `key` if it still holds `value`. Returns whether it did.
+    async fn delete_if_eq(&self, key: &str, value: &[u8]) -> StoreResult<bool>;
+
+    /// Keys starting with `prefix`.
+    async fn scan_prefix(&self, prefix: &str) -> StoreResult<Vec<String>>;
+
+    /// Time until `key` expires. `None` if it doesn't exist or never expires.
+    async fn ttl(&self, key: &str) -> StoreResult<Option<Duration>>;
+
+    /// Keys added to `index` through [`Metadata::indexes`].
+    async fn members(&self, index: &str) -> StoreResult<Vec<String>>;
+
+    /// Adds `by` to the score of `member` in `set` and lets the set expire after `ttl`.
+    async fn incr_score(&self, set: &str, member: &str, by: f64, ttl: Duration) -> StoreResult<()>;
+
+    async fn set_score(&self, set: &str, member: &str, score: f64) -> StoreResult<()>;
+
+    async fn score(&self, set: &str, member: &str) -> StoreResult<Option<f64>>;
+
+    /// Members of all `sets` with their scores summed.
+    async fn scores(&self, sets: &[String]) -> StoreResult<Vec<(String, f64)>>;
+
+    /// Drops the members of `set` scoring below `min`.
+    async fn remove_scores_below(&self, set: &str, min: f64) -> StoreResult<()>;
+}
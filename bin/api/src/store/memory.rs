@@ -0,0 +1,482 @@
+use super::{CacheStore, Metadata, StoreResult};
+use moka::Expiry;
+use moka::ops::compute::{CompResult, Op};
+use moka::sync::Cache;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+struct Value {
+    data: Arc<[u8]>,
+    ttl: Duration,
+    expires_at: Instant,
+}
+
+struct ValueExpiry;
+
+impl Expiry<String, Value> for ValueExpiry {
+    fn expire_after_create(&self, _key: &String, value: &Value, _created_at: Instant) -> Option<Duration> {
+        Some(value.ttl)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &Value,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
+
+/// An index or scored set, kept outside the bounded cache.
+#[derive(Default)]
+struct Collection {
+    members: HashMap<String, f64>,
+    expires_at: Option<Instant>,
+    /// Whether the members are keys of values, see [`PRUNE_INTERVAL`].
+    is_index: bool,
+}
+
+impl Collection {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// How often expired collections are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// How often indexes drop the keys of values that were evicted or deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Collections {
+    entries: HashMap<String, Collection>,
+    swept_at: Instant,
+    pruned_at: Instant,
+}
+
+/// Cache held in the gateway process, bounded to `max_bytes` of keys and values. Eviction follows moka's
+/// TinyLFU policy, so one-off responses don't push out popular ones.
+///
+/// Indexes and scored sets come on top of that bound. Indexes are pruned down to the values still cached every
+/// [`PRUNE_INTERVAL`], so they stay proportional to the values. Hit counters expire with their window, and the
+/// last-access set holds the keys looked up since the refresh service last pruned it.
+pub struct MemoryStore {
+    values: Cache<String, Value>,
+    collections: Mutex<Collections>,
+}
+
+impl MemoryStore {
+    pub fn new(max_bytes: u64) -> Self {
+        let values = Cache::builder()
+            .max_capacity(max_bytes)
+            .weigher(|key: &String, value: &Value| (key.len() + value.data.len()).try_into().unwrap_or(u32::MAX))
+            .expire_after(ValueExpiry)
+            .build();
+
+        Self {
+            values,
+            collections: Mutex::new(Collections {
+                entries: HashMap::new(),
+                swept_at: Instant::now(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Runs `f` on the live collection at `key`, creating it if needed.
+    fn with_collection<T>(&self, key: &str, f: impl FnOnce(&mut Collection) -> T) -> T {
+        let now = Instant::now();
+        let mut collections = self.collections.lock().unwrap();
+        if now.duration_since(collections.swept_at) >= SWEEP_INTERVAL {
+            collections.entries.retain(|_, collection| !collection.is_expired(now));
+            collections.swept_at = now;
+        }
+        if now.duration_since(collections.pruned_at) >= PRUNE_INTERVAL {
+            self.prune_indexes(&mut collections.entries);
+            collections.pruned_at = now;
+        }
+
+        let collection = collections.entries.entry(key.to_string()).or_default();
+        if collection.is_expired(now) {
+            *collection = Collection::default();
+        }
+        f(collection)
+    }
+
+    fn prune_indexes(&self, entries: &mut HashMap<String, Collection>) {
+        entries.retain(|_, collection| {
+            if collection.is_index {
+                collection.members.retain(|key, _| self.values.contains_key(key));
+            }
+            !collection.is_index || !collection.members.is_empty()
+        });
+    }
+
+    fn read_collection<T>(&self, key: &str, f: impl FnOnce(&Collection) -> T) -> Option<T> {
+        let collections = self.collections.lock().unwrap();
+        collections
+            .entries
+            .get(key)
+            .filter(|collection| !collection.is_expired(Instant::now()))
+            .map(f)
+    }
+}
+
+fn value(data: Vec<u8>, ttl: Duration) -> Value {
+    Value {
+        data: data.into(),
+        ttl,
+        expires_at: Instant::now() + ttl,
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>> {
+        Ok(self.values.get(key).map(|value| value.data.to_vec()))
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>, ttl: Duration, meta: &Metadata) -> StoreResult<()> {
+        self.values.insert(key.to_string(), value(data, ttl));
+
+        let expires_at = Instant::now() + ttl;
+        for index in &meta.indexes {
+            self.with_collection(index, |collection| {
+                collection.members.insert(key.to_string(), 0.0);
+                collection.expires_at = collection.expires_at.max(Some(expires_at));
+                collection.is_index = true;
+            });
+        }
+        Ok(())
+    }
+
+    async fn put_if_absent(&self, key: &str, data: Vec<u8>, ttl: Duration) -> StoreResult<bool> {
+        let entry = self.values.entry(key.to_string()).or_insert_with(|| value(data, ttl));
+        Ok(entry.is_fresh())
+    }
+
+    async fn delete(&self, keys: &[String]) -> StoreResult<u64> {
+        let mut collections = self.collections.lock().unwrap();
+        let now = Instant::now();
+
+        let mut deleted = 0;
+        for key in keys {
+            let removed_value = self.values.remove(key).is_some();
+            let removed_collection = collections
+                .entries
+                .remove(key)
+                .is_some_and(|collection| !collection.is_expired(now));
+            if removed_value || removed_collection {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_if_eq(&self, key: &str, data: &[u8]) -> StoreResult<bool> {
+        let result = self
+            .values
+            .entry(key.to_string())
+            .and_compute_with(|entry| match entry {
+                Some(entry) if *entry.value().data == *data => Op::Remove,
+                _ => Op::Nop,
+            });
+        Ok(matches!(result, CompResult::Removed(_)))
+    }
+
+    async fn scan_prefix(&self, prefix: &str) -> StoreResult<Vec<String>> {
+        let now = Instant::now();
+        let mut keys: Vec<String> = self
+            .values
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.as_ref().clone())
+            .collect();
+
+        let collections = self.collections.lock().unwrap();
+        keys.extend(
+            collections
+                .entries
+                .iter()
+                .filter(|(key, collection)| key.starts_with(prefix) && !collection.is_expired(now))
+                .map(|(key, _)| key.clone()),
+        );
+        Ok(keys)
+    }
+
+    async fn ttl(&self, key: &str) -> StoreResult<Option<Duration>> {
+        let now = Instant::now();
+        if let Some(value) = self.values.get(key) {
+            return Ok(Some(value.expires_at.saturating_duration_since(now)));
+        }
+        Ok(self
+            .read_collection(key, |collection| collection.expires_at)
+            .flatten()
+            .map(|at| at.saturating_duration_since(now)))
+    }
+
+    async fn members(&self, index: &str) -> StoreResult<Vec<String>> {
+        Ok(self
+            .read_collection(index, |collection| collection.members.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn incr_score(&self, set: &str, member: &str, by: f64, ttl: Duration) -> StoreResult<()> {
+        self.with_collection(set, |collection| {
+            *collection.members.entry(member.to_string()).or_default() += by;
+            collection.expires_at = Some(Instant::now() + ttl);
+        });
+        Ok(())
+    }
+
+    async fn set_score(&self, set: &str, member: &str, score: f64) -> StoreResult<()> {
+        self.with_collection(set, |collection| {
+            collection.members.insert(member.to_string(), score);
+        });
+        Ok(())
+    }
+
+    async fn score(&self, set: &str, member: &str) -> StoreResult<Option<f64>> {
+        Ok(self
+            .read_collection(set, |collection| collection.members.get(member).copied())
+            .flatten())
+    }
+
+    async fn scores(&self, sets: &[String]) -> StoreResult<Vec<(String, f64)>> {
+        let mut totals: HashMap<String, f64> = HashMap::new();
+        for set in sets {
+            self.read_collection(set, |collection| {
+                for (member, score) in &collection.members {
+                    *totals.entry(member.clone()).or_default() += score;
+                }
+            });
+        }
+        Ok(totals.into_iter().collect())
+    }
+
+    async fn remove_scores_below(&self, set: &str, min: f64) -> StoreResult<()> {
+        self.with_collection(set, |collection| {
+            collection.members.retain(|_, score| *score >= min);
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache, purge, vary};
+
+    const LONG: Duration = Duration::from_secs(60);
+
+    fn store() -> MemoryStore {
+        MemoryStore::new(1024 * 1024)
+    }
+
+    fn indexed(indexes: Vec<String>) -> Metadata {
+        Metadata { indexes }
+    }
+
+    #[tokio::test]
+    async fn stores_values_until_they_expire() {
+        let store = store();
+        store
+            .put("a", b"one".to_vec(), LONG, &Metadata::default())
+            .await
+            .unwrap();
+        store
+            .put("b", b"two".to_vec(), Duration::from_millis(50), &Metadata::default())
+            .await
+            .unwrap();
+
+        assert_eq!(store.get("a").await.unwrap(), Some(b"one".to_vec()));
+        assert_eq!(store.get("b").await.unwrap(), Some(b"two".to_vec()));
+        assert!(store.ttl("a").await.unwrap().is_some_and(|ttl| ttl <= LONG));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.get("b").await.unwrap(), None);
+        assert_eq!(store.ttl("b").await.unwrap(), None);
+        assert_eq!(store.get("missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn conditional_writes_and_deletes() {
+        let store = store();
+        assert!(store.put_if_absent("lock", b"a".to_vec(), LONG).await.unwrap());
+        assert!(!store.put_if_absent("lock", b"b".to_vec(), LONG).await.unwrap());
+
+        assert!(!store.delete_if_eq("lock", b"b").await.unwrap());
+        assert!(store.delete_if_eq("lock", b"a").await.unwrap());
+        assert_eq!(store.get("lock").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn indexes_outlive_their_values() {
+        let store = store();
+        let index = "cache:tag:svc:news".to_string();
+        store
+            .put("proxy:svc:1", b"long".to_vec(), LONG, &indexed(vec![index.clone()]))
+            .await
+            .unwrap();
+        store
+            .put(
+                "proxy:svc:2",
+                b"short".to_vec(),
+                Duration::from_millis(50),
+                &indexed(vec![index.clone()]),
+            )
+            .await
+            .unwrap();
+
+        let mut members = store.members(&index).await.unwrap();
+        members.sort();
+        assert_eq!(members, vec!["proxy:svc:1", "proxy:svc:2"]);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(
+            store
+                .ttl(&index)
+                .await
+                .unwrap()
+                .is_some_and(|ttl| ttl > Duration::from_secs(50))
+        );
+        assert_eq!(store.members(&index).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn prunes_indexes_of_evicted_values() {
+        let store = store();
+        let (kept, emptied) = ("cache:tag:svc:news".to_string(), "cache:tag:svc:sport".to_string());
+        store
+            .put("proxy:svc:1", b"1".to_vec(), LONG, &indexed(vec![kept.clone()]))
+            .await
+            .unwrap();
+        store
+            .put(
+                "proxy:svc:2",
+                b"2".to_vec(),
+                LONG,
+                &indexed(vec![kept.clone(), emptied.clone()]),
+            )
+            .await
+            .unwrap();
+        store.incr_score("hits:1", "proxy:svc:2", 1.0, LONG).await.unwrap();
+        store.values.invalidate("proxy:svc:2");
+
+        store.collections.lock().unwrap().pruned_at -= PRUNE_INTERVAL;
+        store.incr_score("hits:1", "proxy:svc:1", 1.0, LONG).await.unwrap();
+
+        assert_eq!(store.members(&kept).await.unwrap(), vec!["proxy:svc:1"]);
+        assert_eq!(store.ttl(&emptied).await.unwrap(), None);
+        assert_eq!(store.score("hits:1", "proxy:svc:2").await.unwrap(), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn purges_entries_by_tag() {
+        let store = store();
+        let news = purge::tag_indexes("svc", &["news".to_string()]);
+        store
+            .put("proxy:svc:1", b"1".to_vec(), LONG, &indexed(news.clone()))
+            .await
+            .unwrap();
+        store
+            .put("proxy:svc:2", b"2".to_vec(), LONG, &indexed(news))
+            .await
+            .unwrap();
+        store
+            .put("proxy:svc:3", b"3".to_vec(), LONG, &Metadata::default())
+            .await
+            .unwrap();
+
+        assert_eq!(purge::purge_tag(&store, "svc", "news").await.unwrap(), 2);
+        assert_eq!(store.get("proxy:svc:1").await.unwrap(), None);
+        assert_eq!(store.get("proxy:svc:2").await.unwrap(), None);
+        assert_eq!(store.get("proxy:svc:3").await.unwrap(), Some(b"3".to_vec()));
+        assert!(
+            store
+                .members(&purge::tag_indexes("svc", &["news".to_string()])[0])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn purges_all_variants_of_a_url() {
+        let store = store();
+        let variants = vec![vary::variants_key("svc", "primary")];
+        let fields = vec!["accept-language".to_string()];
+        let mut headers = axum::http::HeaderMap::new();
+        for language in ["en", "de"] {
+            headers.insert("accept-language", language.parse().unwrap());
+            let key = vary::variant_key("svc", "primary", &fields, &headers);
+            store
+                .put(&key, language.into(), LONG, &indexed(variants.clone()))
+                .await
+                .unwrap();
+        }
+        vary::record(&store, "svc", "primary", &fields, LONG).await.unwrap();
+        store
+            .put(&cache::key("svc", "other"), b"x".to_vec(), LONG, &Metadata::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vary::lookup_key(&store, "svc", "primary", &headers).await,
+            vary::variant_key("svc", "primary", &fields, &headers)
+        );
+        assert_eq!(vary::purge(&store, "svc", "primary").await.unwrap(), 2);
+        assert_eq!(
+            vary::lookup_key(&store, "svc", "primary", &headers).await,
+            cache::key("svc", "primary")
+        );
+        assert!(store.members(&variants[0]).await.unwrap().is_empty());
+        assert!(store.get(&cache::key("svc", "other")).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn purges_a_whole_service() {
+        let store = store();
+        let tags = purge::tag_indexes("svc", &["news".to_string()]);
+        store
+            .put(&cache::key("svc", "a"), b"a".to_vec(), LONG, &indexed(tags))
+            .await
+            .unwrap();
+        store
+            .put(&cache::key("svc", "b"), b"b".to_vec(), LONG, &Metadata::default())
+            .await
+            .unwrap();
+        store
+            .put(&cache::key("other", "a"), b"a".to_vec(), LONG, &Metadata::default())
+            .await
+            .unwrap();
+
+        assert_eq!(purge::purge_service(&store, "svc").await.unwrap(), 2);
+        assert!(store.scan_prefix("cache:tag:svc:").await.unwrap().is_empty());
+        assert!(store.scan_prefix(&cache::key("svc", "")).await.unwrap().is_empty());
+        assert_eq!(store.scan_prefix(&cache::key("other", "")).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_scores() {
+        let store = store();
+        store.incr_score("hits:1", "a", 2.0, LONG).await.unwrap();
+        store.incr_score("hits:1", "a", 1.0, LONG).await.unwrap();
+        store.incr_score("hits:2", "a", 1.0, LONG).await.unwrap();
+        store.incr_score("hits:2", "b", 1.0, LONG).await.unwrap();
+        store.set_score("last", "a", 10.0).await.unwrap();
+        store.set_score("last", "b", 20.0).await.unwrap();
+
+        let mut scores = store.scores(&["hits:1".into(), "hits:2".into()]).await.unwrap();
+        scores.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(scores, vec![("a".to_string(), 4.0), ("b".to_string(), 1.0)]);
+
+        store.remove_scores_below("last", 15.0).await.unwrap();
+        assert_eq!(store.score("last", "a").await.unwrap(), None);
+        assert_eq!(store.score("last", "b").await.unwrap(), Some(20.0));
+    }
+}
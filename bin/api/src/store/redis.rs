@@ -0,0 +1,295 @@
+use super::{CacheStore, Metadata, StoreResult};
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use std::time::Duration;
+
+const DELETE_IF_EQ_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Cache shared by all gateways through Redis or DragonflyDB. Indexes are sets and scored sets are sorted sets.
+#[derive(Clone)]
+pub struct RedisStore {
+    redis: ConnectionManager,
+}
+
+impl RedisStore {
+    pub async fn connect(addr: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(addr)?;
+        let redis = ConnectionManager::new(client).await?;
+        Ok(Self { redis })
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>> {
+        Ok(self.redis.clone().get(key).await?)
+    }
+
+    /// Reads the value and its `PTTL` in one round trip.
+    async fn get_with_ttl(&self, key: &str) -> StoreResult<Option<(Vec<u8>, Option<Duration>)>> {
+        let (value, millis): (Option<Vec<u8>>, i64) = redis::pipe()
+            .get(key)
+            .pttl(key)
+            .query_async(&mut self.redis.clone())
+            .await?;
+        let ttl = (millis >= 0).then(|| Duration::from_millis(millis as u64));
+        Ok(value.map(|value| (value, ttl)))
+    }
+
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Duration, meta: &Metadata) -> StoreResult<()> {
+        let expiry = millis(ttl);
+
+        let mut pipe = redis::pipe();
+        pipe.pset_ex(key, value, expiry).ignore();
+        // Indexes are shared with longer-lived values, so their expiry is only ever extended. `GT` treats a set
+        // without expiry as never expiring, so a new set gets one through `NX` first.
+        for index in &meta.indexes {
+            pipe.sadd(index, key).ignore();
+            pipe.cmd("PEXPIRE").arg(index).arg(expiry).arg("NX").ignore();
+            pipe.cmd("PEXPIRE").arg(index).arg(expiry).arg("GT").ignore();
+        }
+        Ok(pipe.query_async(&mut self.redis.clone()).await?)
+    }
+
+    async fn put_if_absent(&self, key: &str, value: Vec<u8>, ttl: Duration) -> StoreResult<bool> {
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::PX(millis(ttl)));
+
+        let stored: Option<String> = self.redis.clone().set_options(key, value, options).await?;
+        Ok(stored.is_some())
+    }
+
+    async fn delete(&self, keys: &[String]) -> StoreResult<u64> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.redis.clone().del(keys).await?)
+    }
+
+    async fn delete_if_eq(&self, key: &str, value: &[u8]) -> StoreResult<bool> {
+        let deleted: i64 = redis::Script::new(DELETE_IF_EQ_SCRIPT)
+            .key(key)
+            .arg(value)
+            .invoke_async(&mut self.redis.clone())
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Walks the keyspace with `SCAN`.
+    async fn scan_prefix(&self, prefix: &str) -> StoreResult<Vec<String>> {
+        let mut redis = self.redis.clone();
+        let pattern = format!("{}*", escape_pattern(prefix));
+        let mut cursor: u64 = 0;
+        let mut found = Vec::new();
+
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(100)
+                .query_async(&mut redis)
+                .await?;
+            found.extend(keys);
+
+            cursor = next;
+            if cursor == 0 {
+                return Ok(found);
+            }
+        }
+    }
+
+    async fn ttl(&self, key: &str) -> StoreResult<Option<Duration>> {
+        let millis: i64 = self.redis.clone().pttl(key).await?;
+        Ok((millis >= 0).then(|| Duration::from_millis(millis as u64)))
+    }
+
+    async fn members(&self, index: &str) -> StoreResult<Vec<String>> {
+        Ok(self.redis.clone().smembers(index).await?)
+    }
+
+    async fn incr_score(&self, set: &str, member: &str, by: f64, ttl: Duration) -> StoreResult<()> {
+        Ok(redis::pipe()
+            .zincr(set, member, by)
+            .ignore()
+            .pexpire(set, millis(ttl) as i64)
+            .ignore()
+            .query_async(&mut self.redis.clone())
+            .await?)
+    }
+
+    async fn set_score(&self, set: &str, member: &str, score: f64) -> StoreResult<()> {
+        Ok(self.redis.clone().zadd(set, member, score).await?)
+    }
+
+    async fn score(&self, set: &str, member: &str) -> StoreResult<Option<f64>> {
+        Ok(self.redis.clone().zscore(set, member).await?)
+    }
+
+    async fn scores(&self, sets: &[String]) -> StoreResult<Vec<(String, f64)>> {
+        Ok(redis::cmd("ZUNION")
+            .arg(sets.len())
+            .arg(sets)
+            .arg("WITHSCORES")
+            .query_async(&mut self.redis.clone())
+            .await?)
+    }
+
+    async fn remove_scores_below(&self, set: &str, min: f64) -> StoreResult<()> {
+        Ok(redis::cmd("ZREMRANGEBYSCORE")
+            .arg(set)
+            .arg("-inf")
+            .arg(format!("({}", min))
+            .query_async(&mut self.redis.clone())
+            .await?)
+    }
+}
+
+/// Expiry in milliseconds for `PX`/`PEXPIRE`, which reject 0.
+fn millis(ttl: Duration) -> u64 {
+    (ttl.as_millis() as u64).max(1)
+}
+
+/// Escapes the glob characters of `MATCH` patterns.
+fn escape_pattern(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// These run against the Redis or DragonflyDB at `REDIS_TEST_ADDR`, e.g. the one from `docker-compose up -d`, and
+/// pass without running when it isn't set. Every test works below its own random key prefix.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Duration = Duration::from_secs(60);
+
+    async fn store() -> Option<(RedisStore, String)> {
+        let Ok(addr) = std::env::var("REDIS_TEST_ADDR") else {
+            eprintln!("REDIS_TEST_ADDR is not set, skipping");
+            return None;
+        };
+        let store = RedisStore::connect(&addr)
+            .await
+            .expect("failed to connect to REDIS_TEST_ADDR");
+        Some((store, format!("prtl-test:{}:", uuid::Uuid::new_v4())))
+    }
+
+    #[tokio::test]
+    async fn keeps_sub_second_ttls() {
+        let Some((store, prefix)) = store().await else { return };
+        let key = format!("{}a", prefix);
+
+        store
+            .put(&key, b"one".to_vec(), Duration::from_millis(300), &Metadata::default())
+            .await
+            .unwrap();
+        let (value, ttl) = store.get_with_ttl(&key).await.unwrap().unwrap();
+        assert_eq!(value, b"one");
+        assert!(ttl.is_some_and(|ttl| ttl <= Duration::from_millis(300)));
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(store.get_with_ttl(&key).await.unwrap(), None);
+        assert_eq!(store.ttl(&key).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn indexes_outlive_their_values() {
+        let Some((store, prefix)) = store().await else { return };
+        let index = format!("{}index", prefix);
+        let meta = Metadata {
+            indexes: vec![index.clone()],
+        };
+
+        store
+            .put(&format!("{}long", prefix), Vec::new(), LONG, &meta)
+            .await
+            .unwrap();
+        store
+            .put(&format!("{}short", prefix), Vec::new(), Duration::from_secs(1), &meta)
+            .await
+            .unwrap();
+
+        // The shorter value doesn't shorten the index
+        let ttl = store.ttl(&index).await.unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(30) && ttl <= LONG);
+
+        let mut members = store.members(&index).await.unwrap();
+        members.sort();
+        assert_eq!(members, [format!("{}long", prefix), format!("{}short", prefix)]);
+
+        store.delete(&[index]).await.unwrap();
+        store.delete(&store.scan_prefix(&prefix).await.unwrap()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn puts_and_deletes_conditionally() {
+        let Some((store, prefix)) = store().await else { return };
+        let lock = format!("{}lock", prefix);
+
+        assert!(store.put_if_absent(&lock, b"a".to_vec(), LONG).await.unwrap());
+        assert!(!store.put_if_absent(&lock, b"b".to_vec(), LONG).await.unwrap());
+        assert!(!store.delete_if_eq(&lock, b"b").await.unwrap());
+        assert!(store.delete_if_eq(&lock, b"a").await.unwrap());
+        assert_eq!(store.get(&lock).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn scans_prefixes_literally() {
+        let Some((store, prefix)) = store().await else { return };
+        let keys = [
+            format!("{}a*", prefix),
+            format!("{}a*b", prefix),
+            format!("{}ab", prefix),
+        ];
+        for key in &keys {
+            store.put(key, Vec::new(), LONG, &Metadata::default()).await.unwrap();
+        }
+
+        let mut found = store.scan_prefix(&format!("{}a*", prefix)).await.unwrap();
+        found.sort();
+        assert_eq!(found, keys[..2]);
+
+        assert_eq!(store.delete(&keys).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn sums_scores_across_sets() {
+        let Some((store, prefix)) = store().await else { return };
+        let sets = [format!("{}hits:1", prefix), format!("{}hits:2", prefix)];
+
+        store.incr_score(&sets[0], "a", 2.0, LONG).await.unwrap();
+        store.incr_score(&sets[0], "b", 1.0, LONG).await.unwrap();
+        store.incr_score(&sets[1], "a", 3.0, LONG).await.unwrap();
+        assert!(store.ttl(&sets[0]).await.unwrap().is_some());
+
+        let mut scores = store.scores(&sets).await.unwrap();
+        scores.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(scores, [("a".to_string(), 5.0), ("b".to_string(), 1.0)]);
+
+        let last_access = format!("{}last_access", prefix);
+        store.set_score(&last_access, "a", 10.0).await.unwrap();
+        store.set_score(&last_access, "b", 20.0).await.unwrap();
+        store.remove_scores_below(&last_access, 20.0).await.unwrap();
+        assert_eq!(store.score(&last_access, "a").await.unwrap(), None);
+        assert_eq!(store.score(&last_access, "b").await.unwrap(), Some(20.0));
+
+        store
+            .delete(&[sets[0].clone(), sets[1].clone(), last_access])
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+use super::{CacheStore, MemoryStore, Metadata, StoreResult};
+use crate::cache;
+use crate::vary;
+use prtl_messages::{BusMessage, CacheEviction};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long changed keys are collected before they are broadcast in one [`CacheEviction`].
+const EVICTION_BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lookups answered and missed by one tier.
+#[derive(Debug, Default, Serialize)]
+pub struct TierCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TierCounters {
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Cache entry lookups per tier since the gateway started. Every L1 miss is an L2 lookup.
+#[derive(Debug, Default, Serialize)]
+pub struct TierStats {
+    pub l1: TierCounters,
+    pub l2: TierCounters,
+}
+
+/// Cache entries kept in the process (L1) in front of a shared store (L2), usually Redis.
+///
+/// Cache entries and the `Vary` fields read before every entry lookup go through L1, for at most `l1_ttl` and never
+/// longer than they have left in L2; indexes, counters and locks always use L2. Keys written or deleted here are
+/// broadcast in batches by [`TieredStore::broadcast_evictions`] so other gateways drop their L1 copies, see
+/// [`TieredStore::apply_eviction`].
+pub struct TieredStore {
+    l1: MemoryStore,
+    l2: Arc<dyn CacheStore>,
+    l1_ttl: Duration,
+    stats: Arc<TierStats>,
+    /// Keys changed since the last broadcast.
+    changed: Mutex<Vec<String>>,
+    gateway_id: String,
+}
+
+impl TieredStore {
+    pub fn new(l2: Arc<dyn CacheStore>, l1_max_bytes: u64, l1_ttl: Duration, gateway_id: String) -> Self {
+        Self {
+            l1: MemoryStore::new(l1_max_bytes),
+            l2,
+            l1_ttl,
+            stats: Arc::default(),
+            changed: Mutex::default(),
+            gateway_id,
+        }
+    }
+
+    pub fn stats(&self) -> Arc<TierStats> {
+        self.stats.clone()
+    }
+
+    /// Drops the L1 copies of entries changed by another gateway.
+    pub async fn apply_eviction(&self, eviction: &CacheEviction) -> StoreResult<()> {
+        if eviction.gateway_id == self.gateway_id {
+            return Ok(());
+        }
+        self.l1.delete(&eviction.keys).await.map(|_| ())
+    }
+
+    /// Publishes the keys changed here on `subject` every [`EVICTION_BATCH_INTERVAL`], one message per batch.
+    pub async fn broadcast_evictions(self: Arc<Self>, nats: async_nats::Client, subject: String) {
+        let mut interval = tokio::time::interval(EVICTION_BATCH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let Some(eviction) = self.take_eviction() else {
+                continue;
+            };
+            let payload = match rmp_serde::to_vec_named(&BusMessage::CacheEviction(eviction)) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    tracing::error!("Failed to serialize cache eviction: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = nats.publish(subject.clone(), payload.into()).await {
+                tracing::warn!("Failed to broadcast cache eviction: {}", e);
+            }
+        }
+    }
+
+    /// The keys changed since the last call, deduplicated, if there are any.
+    fn take_eviction(&self) -> Option<CacheEviction> {
+        let mut keys = std::mem::take(&mut *self.changed.lock().unwrap());
+        if keys.is_empty() {
+            return None;
+        }
+        keys.sort();
+        keys.dedup();
+        Some(CacheEviction {
+            gateway_id: self.gateway_id.clone(),
+            keys,
+        })
+    }
+
+    fn mark_changed(&self, keys: impl IntoIterator<Item = String>) {
+        self.changed.lock().unwrap().extend(keys);
+    }
+}
+
+fn is_entry(key: &str) -> bool {
+    key.starts_with(cache::KEY_PREFIX)
+}
+
+fn is_in_l1(key: &str) -> bool {
+    is_entry(key) || key.starts_with(vary::FIELDS_PREFIX)
+}
+
+#[async_trait::async_trait]
+impl CacheStore for TieredStore {
+    async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>> {
+        if !is_in_l1(key) {
+            return self.l2.get(key).await;
+        }
+
+        // Only entry lookups are counted.
+        let entry = is_entry(key);
+        let record = |counters: &TierCounters, hit| {
+            if entry {
+                counters.record(hit);
+            }
+        };
+
+        if let Some(value) = self.l1.get(key).await? {
+            record(&self.stats.l1, true);
+            return Ok(Some(value));
+        }
+        record(&self.stats.l1, false);
+
+        let value = self.l2.get_with_ttl(key).await?;
+        record(&self.stats.l2, value.is_some());
+        let Some((value, ttl)) = value else {
+            return Ok(None);
+        };
+
+        // The copy must not outlive the entry in L2.
+        let l1_ttl = ttl.map_or(self.l1_ttl, |ttl| ttl.min(self.l1_ttl));
+        if !l1_ttl.is_zero() {
+            self.l1.put(key, value.clone(), l1_ttl, &Metadata::default()).await?;
+        }
+        Ok(Some(value))
+    }
+
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Duration, meta: &Metadata) -> StoreResult<()> {
+        if !is_in_l1(key) {
+            return self.l2.put(key, value, ttl, meta).await;
+        }
+
+        self.l2.put(key, value.clone(), ttl, meta).await?;
+        self.l1
+            .put(key, value, ttl.min(self.l1_ttl), &Metadata::default())
+            .await?;
+        self.mark_changed([key.to_string()]);
+        Ok(())
+    }
+
+    async fn put_if_absent(&self, key: &str, value: Vec<u8>, ttl: Duration) -> StoreResult<bool> {
+        self.l2.put_if_absent(key, value, ttl).await
+    }
+
+    async fn delete(&self, keys: &[String]) -> StoreResult<u64> {
+        let deleted = self.l2.delete(keys).await?;
+
+        let local: Vec<String> = keys.iter().filter(|key| is_in_l1(key)).cloned().collect();
+        self.l1.delete(&local).await?;
+        self.mark_changed(local);
+        Ok(deleted)
+    }
+
+    async fn delete_if_eq(&self, key: &str, value: &[u8]) -> StoreResult<bool> {
+        self.l2.delete_if_eq(key, value).await
+    }
+
+    async fn scan_prefix(&self, prefix: &str) -> StoreResult<Vec<String>> {
+        self.l2.scan_prefix(prefix).await
+    }
+
+    async fn ttl(&self, key: &str) -> StoreResult<Option<Duration>> {
+        self.l2.ttl(key).await
+    }
+
+    async fn members(&self, index: &str) -> StoreResult<Vec<String>> {
+        self.l2.members(index).await
+    }
+
+    async fn incr_score(&self, set: &str, member: &str, by: f64, ttl: Duration) -> StoreResult<()> {
+        self.l2.incr_score(set, member, by, ttl).await
+    }
+
+    async fn set_score(&self, set: &str, member: &str, score: f64) -> StoreResult<()> {
+        self.l2.set_score(set, member, score).await
+    }
+
+    async fn score(&self, set: &str, member: &str) -> StoreResult<Option<f64>> {
+        self.l2.score(set, member).await
+    }
+
+    async fn scores(&self, sets: &[String]) -> StoreResult<Vec<(String, f64)>> {
+        self.l2.scores(sets).await
+    }
+
+    async fn remove_scores_below(&self, set: &str, min: f64) -> StoreResult<()> {
+        self.l2.remove_scores_below(set, min).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderMap;
+
+    const LONG: Duration = Duration::from_secs(60);
+
+    fn tiered() -> (TieredStore, Arc<MemoryStore>) {
+        let l2 = Arc::new(MemoryStore::new(1024 * 1024));
+        (TieredStore::new(l2.clone(), 1024 * 1024, LONG, "self".to_string()), l2)
+    }
+
+    fn counts(counters: &TierCounters) -> (u64, u64) {
+        (
+            counters.hits.load(Ordering::Relaxed),
+            counters.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    #[tokio::test]
+    async fn counts_entry_lookups_per_tier() {
+        let (store, l2) = tiered();
+        let key = cache::key("svc", "a");
+        l2.put(&key, b"a".to_vec(), LONG, &Metadata::default()).await.unwrap();
+
+        assert_eq!(store.get(&key).await.unwrap(), Some(b"a".to_vec()));
+        assert_eq!(store.get(&key).await.unwrap(), Some(b"a".to_vec()));
+        assert_eq!(store.get(&cache::key("svc", "missing")).await.unwrap(), None);
+        vary::lookup_key(&store, "svc", "a", &HeaderMap::new()).await;
+
+        assert_eq!(counts(&store.stats.l1), (1, 2));
+        assert_eq!(counts(&store.stats.l2), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn copies_never_outlive_l2() {
+        let (store, l2) = tiered();
+        let key = cache::key("svc", "a");
+        l2.put(&key, b"a".to_vec(), Duration::from_millis(50), &Metadata::default())
+            .await
+            .unwrap();
+
+        assert_eq!(store.get(&key).await.unwrap(), Some(b"a".to_vec()));
+        assert!(
+            store
+                .l1
+                .ttl(&key)
+                .await
+                .unwrap()
+                .is_some_and(|ttl| ttl <= Duration::from_millis(50))
+        );
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.get(&key).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_empty_vary_fields_but_not_missing_ones() {
+        let (store, l2) = tiered();
+        let mut headers = HeaderMap::new();
+        headers.insert("accept-language", "de".parse().unwrap());
+
+        // A URL nothing was recorded for yet is looked up in L2 every time, so fields recorded later apply at once.
+        let fields = vec!["accept-language".to_string()];
+        assert_eq!(
+            vary::lookup_key(&store, "svc", "a", &headers).await,
+            cache::key("svc", "a")
+        );
+        vary::record(l2.as_ref(), "svc", "a", &fields, LONG).await.unwrap();
+        assert_eq!(
+            vary::lookup_key(&store, "svc", "a", &headers).await,
+            vary::variant_key("svc", "a", &fields, &headers)
+        );
+
+        // Recorded fields, empty or not, are kept in L1.
+        vary::record(&store, "svc", "b", &[], LONG).await.unwrap();
+        l2.delete(&[format!("{}svc:b", vary::FIELDS_PREFIX)]).await.unwrap();
+        vary::record(l2.as_ref(), "svc", "b", &fields, LONG).await.unwrap();
+        assert_eq!(
+            vary::lookup_key(&store, "svc", "b", &headers).await,
+            cache::key("svc", "b")
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_changed_keys() {
+        let (store, _) = tiered();
+        let (a, b) = (cache::key("svc", "a"), cache::key("svc", "b"));
+        assert!(store.take_eviction().is_none());
+
+        store.put(&a, b"a".to_vec(), LONG, &Metadata::default()).await.unwrap();
+        store.put(&b, b"b".to_vec(), LONG, &Metadata::default()).await.unwrap();
+        store
+            .delete(&[a.clone(), "cache:tag:svc:news".to_string()])
+            .await
+            .unwrap();
+        store.incr_score("hits:1", &b, 1.0, LONG).await.unwrap();
+
+        let eviction = store.take_eviction().unwrap();
+        assert_eq!(eviction.gateway_id, "self");
+        assert_eq!(eviction.keys, vec![a, b]);
+        assert!(store.take_eviction().is_none());
+    }
+
+    #[tokio::test]
+    async fn applies_evictions_from_other_gateways_only() {
+        let (store, l2) = tiered();
+        let key = cache::key("svc", "a");
+        store
+            .put(&key, b"a".to_vec(), LONG, &Metadata::default())
+            .await
+            .unwrap();
+        l2.put(&key, b"b".to_vec(), LONG, &Metadata::default()).await.unwrap();
+
+        let eviction = |gateway_id: &str| CacheEviction {
+            gateway_id: gateway_id.to_string(),
+            keys: vec![key.clone()],
+        };
+        store.apply_eviction(&eviction("self")).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), Some(b"a".to_vec()));
+
+        store.apply_eviction(&eviction("other")).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), Some(b"b".to_vec()));
+    }
+}
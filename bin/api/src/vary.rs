@@ -0,0 +1,191 @@
+//! Responses that vary on request headers are stored under a secondary key per variant: the primary hash (URL,
+//! query and `key_headers`) combined with the values of the headers named by `Vary`.
+//!
+//! For each primary hash the gateway remembers the `Vary` fields of the latest response in `cache:vary:{s}:{h}`,
+//! so lookups know which headers pick the variant, and the keys of all variants in `cache:variants:{s}:{h}`, so a
+//! URL can be purged with all of them.
+
+use crate::cache;
+use crate::hash;
+use crate::store::{CacheStore, Metadata, StoreResult};
+use axum::http::HeaderMap;
+use axum::http::header::VARY;
+use std::time::Duration;
+
+/// Prefix of the keys holding the `Vary` fields recorded for a primary hash.
+pub const FIELDS_PREFIX: &str = "cache:vary:";
+
+fn fields_key(service_name: &str, primary: &str) -> String {
+    format!("{}{}:{}", FIELDS_PREFIX, service_name, primary)
+}
+
+/// Index of the keys of all variants of `primary`, to be listed in when storing a variant.
+pub fn variants_key(service_name: &str, primary: &str) -> String {
+    format!("cache:variants:{}:{}", service_name, primary)
+}
+
+/// Prefixes of the `Vary` records of every entry of a service, both fields and variant indexes.
+pub fn service_prefixes(service_name: &str) -> [String; 2] {
+    [fields_key(service_name, ""), variants_key(service_name, "")]
+}
+
+/// The header names listed by `Vary`, lowercased and sorted. `None` for `Vary: *`, which no key can capture.
+pub fn fields(headers: &HeaderMap) -> Option<Vec<String>> {
+    let mut fields: Vec<String> = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|field| field.trim().to_ascii_lowercase())
+        .filter(|field| !field.is_empty())
+        .collect();
+
+    if fields.iter().any(|field| field == "*") {
+        return None;
+    }
+    fields.sort();
+    fields.dedup();
+    Some(fields)
+}
+
+/// Cache key of the variant of `primary` selected by `request_headers`.
+pub fn variant_key(service_name: &str, primary: &str, fields: &[String], request_headers: &HeaderMap) -> String {
+    if fields.is_empty() {
+        return cache::key(service_name, primary);
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    hash::update_field(&mut hasher, b"primary", primary.as_bytes());
+    for field in fields {
+        hash::update_field(&mut hasher, b"vary", field.as_bytes());
+        for value in request_headers.get_all(field.as_str()) {
+            hash::update_prefixed(&mut hasher, value.as_bytes());
+        }
+    }
+    cache::key(service_name, &hasher.finalize().to_hex())
+}
+
+/// Whether a response fetched for a request with `fetched_for` headers also answers one with `request_headers`:
+/// both send the same values for every field the response varies on, or every header at all for `Vary: *`.
+pub fn same_variant(response_headers: &HeaderMap, fetched_for: &HeaderMap, request_headers: &HeaderMap) -> bool {
+    let Some(fields) = fields(response_headers) else {
+        return fetched_for == request_headers;
+    };
+    fields.iter().all(|field| {
+        fetched_for
+            .get_all(field.as_str())
+            .iter()
+            .eq(request_headers.get_all(field.as_str()).iter())
+    })
+}
+
+/// Cache key to look up for a request, following the `Vary` fields recorded for its primary hash.
+pub async fn lookup_key(
+    store: &dyn CacheStore,
+    service_name: &str,
+    primary: &str,
+    request_headers: &HeaderMap,
+) -> String {
+    let fields = store.get(&fields_key(service_name, primary)).await.unwrap_or_else(|e| {
+        tracing::debug!("Failed to read vary fields of {}: {}", primary, e);
+        None
+    });
+
+    let fields: Vec<String> = String::from_utf8(fields.unwrap_or_default())
+        .unwrap_or_default()
+        .split(',')
+        .filter(|field| !field.is_empty())
+        .map(str::to_string)
+        .collect();
+    variant_key(service_name, primary, &fields, request_headers)
+}
+
+/// Records the `Vary` fields of a stored response, for as long as the entry lives. Responses without `Vary` get an
+/// empty record too, so tiered stores can keep the answer for the many URLs that don't vary. The variant itself is
+/// listed under [`variants_key`] when it is stored.
+pub async fn record(
+    store: &dyn CacheStore,
+    service_name: &str,
+    primary: &str,
+    fields: &[String],
+    expiry: Duration,
+) -> StoreResult<()> {
+    store
+        .put(
+            &fields_key(service_name, primary),
+            fields.join(",").into_bytes(),
+            expiry,
+            &Metadata::default(),
+        )
+        .await
+}
+
+/// Deletes every variant stored for `primary` and returns how many entries were removed.
+pub async fn purge(store: &dyn CacheStore, service_name: &str, primary: &str) -> StoreResult<u64> {
+    let variants_key = variants_key(service_name, primary);
+    let mut keys = store.members(&variants_key).await?;
+    keys.push(cache::key(service_name, primary));
+    keys.sort();
+    keys.dedup();
+
+    let (entries, chunks) = cache::partition_entries(keys);
+    let purged = store.delete(&entries).await?;
+    store.delete(&chunks).await?;
+    store.delete(&[variants_key, fields_key(service_name, primary)]).await?;
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn fields_are_normalized() {
+        let response = headers(&[
+            ("vary", "Accept-Encoding, accept-language"),
+            ("vary", "accept-encoding"),
+        ]);
+        assert_eq!(
+            fields(&response),
+            Some(vec!["accept-encoding".into(), "accept-language".into()])
+        );
+        assert_eq!(fields(&headers(&[("vary", "accept, *")])), None);
+    }
+
+    #[test]
+    fn variants_follow_varying_headers_only() {
+        let fields = vec!["accept-language".to_string()];
+        let en = headers(&[("accept-language", "en"), ("user-agent", "a")]);
+        let en_other_agent = headers(&[("accept-language", "en"), ("user-agent", "b")]);
+        let de = headers(&[("accept-language", "de")]);
+
+        let key = variant_key("svc", "hash", &fields, &en);
+        assert_eq!(key, variant_key("svc", "hash", &fields, &en_other_agent));
+        assert_ne!(key, variant_key("svc", "hash", &fields, &de));
+        assert_eq!(variant_key("svc", "hash", &[], &en), cache::key("svc", "hash"));
+    }
+
+    #[test]
+    fn shared_responses_only_serve_the_same_variant() {
+        let response = headers(&[("vary", "accept-language")]);
+        let en = headers(&[("accept-language", "en"), ("user-agent", "a")]);
+        let en_other_agent = headers(&[("accept-language", "en"), ("user-agent", "b")]);
+        let de = headers(&[("accept-language", "de")]);
+
+        assert!(same_variant(&response, &en, &en_other_agent));
+        assert!(!same_variant(&response, &en, &de));
+        assert!(!same_variant(&response, &en, &HeaderMap::new()));
+        assert!(same_variant(&HeaderMap::new(), &en, &de));
+
+        let any = headers(&[("vary", "*")]);
+        assert!(same_variant(&any, &en, &en));
+        assert!(!same_variant(&any, &en, &en_other_agent));
+    }
+}
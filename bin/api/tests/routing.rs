@@ -0,0 +1,75 @@
+mod support;
+
+use http::{Request, Response};
+use prtl_proxy::messages::{HashComponents, ProxyDescriptor, Subjects};
+use prtl_proxy::{BoxError, PrtlService, ServeConfig};
+use std::sync::Arc;
+use std::time::Duration;
+use support::Gateway;
+use support::nats::NatsServer;
+
+const SUBJECT_PREFIX: &str = "prtl-test";
+
+struct Echo;
+
+#[async_trait::async_trait]
+impl PrtlService for Echo {
+    fn descriptor(&self) -> ProxyDescriptor {
+        ProxyDescriptor {
+            service_name: "echo".into(),
+            base_domains: vec!["echo.test".into()],
+            hash_settings: HashComponents::URL | HashComponents::QUERY,
+            cache_ttl: None,
+            ..Default::default()
+        }
+    }
+
+    async fn handle_request(&self, request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, BoxError> {
+        Ok(Response::builder()
+            .status(200)
+            .header("x-echo-method", request.method().as_str())
+            .body(request.uri().to_string().into_bytes())?)
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn routes_requests_to_registered_proxy() {
+    let nats = NatsServer::start().await;
+
+    let gateway = Gateway::spawn(&[
+        ("NATS_ADDR", &nats.url()),
+        ("CACHE_BACKEND", "memory"),
+        ("PRTL_SUBJECT_PREFIX", SUBJECT_PREFIX),
+    ]);
+
+    let config = ServeConfig {
+        nats_addr: nats.url(),
+        subjects: Subjects::new(SUBJECT_PREFIX),
+        ..ServeConfig::default()
+    };
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let proxy = tokio::spawn(prtl_proxy::serve_with_config(Arc::new(Echo), config, async {
+        let _ = shutdown_rx.await;
+    }));
+
+    // The gateway answers 503 until the proxy has registered, and refuses connections until it is listening
+    let client = reqwest::Client::new();
+    let url = gateway.url("/echo.test/hello?name=prtl");
+    let mut response = None;
+    for _ in 0..100 {
+        if let Ok(r) = client.get(&url).send().await
+            && r.status().is_success()
+        {
+            response = Some(r);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let response = response.expect("gateway never routed the request to the proxy");
+    assert_eq!(response.headers()["x-echo-method"], "GET");
+    assert_eq!(response.text().await.unwrap(), "https://echo.test/hello?name=prtl");
+
+    shutdown_tx.send(()).unwrap();
+    proxy.await.unwrap().unwrap();
+}
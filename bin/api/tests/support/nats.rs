@@ -0,0 +1,203 @@
+//! A minimal in-process NATS server. It speaks just enough of the client protocol for async-nats: `PUB`/`HPUB`,
+//! `SUB` with queue groups and wildcards, `UNSUB`, `PING`/`PONG` and no-responders replies.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+const NO_RESPONDERS: &[u8] = b"NATS/1.0 503\r\n\r\n";
+
+struct Subscription {
+    conn: usize,
+    sid: String,
+    subject: String,
+    queue: Option<String>,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Broker {
+    subs: Mutex<Vec<Subscription>>,
+    next_conn: AtomicUsize,
+    next_pick: AtomicUsize,
+}
+
+pub struct NatsServer {
+    addr: SocketAddr,
+}
+
+impl NatsServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = Arc::new(Broker::default());
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(broker.clone().handle_connection(stream, addr));
+            }
+        });
+
+        Self { addr }
+    }
+
+    pub fn url(&self) -> String {
+        format!("nats://{}", self.addr)
+    }
+}
+
+impl Broker {
+    async fn handle_connection(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) {
+        let conn = self.next_conn.fetch_add(1, Ordering::Relaxed);
+        let (read, mut write) = stream.into_split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        tokio::spawn(async move {
+            while let Some(frame) = rx.recv().await {
+                if write.write_all(&frame).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let info = format!(
+            "INFO {{\"server_id\":\"prtl-test\",\"server_name\":\"prtl-test\",\"version\":\"2.10.0\",\"proto\":1,\
+             \"host\":\"{}\",\"port\":{},\"headers\":true,\"max_payload\":1048576}}\r\n",
+            addr.ip(),
+            addr.port()
+        );
+        let _ = tx.send(info.into_bytes());
+
+        let mut reader = BufReader::new(read);
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            match reader.read_line(&mut line).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+
+            let mut parts = line.split_whitespace();
+            let Some(op) = parts.next() else {
+                continue;
+            };
+            let args: Vec<&str> = parts.collect();
+
+            match op.to_ascii_uppercase().as_str() {
+                "PING" => {
+                    let _ = tx.send(b"PONG\r\n".to_vec());
+                }
+                "SUB" => {
+                    let (subject, queue, sid) = match args[..] {
+                        [subject, sid] => (subject, None, sid),
+                        [subject, queue, sid] => (subject, Some(queue.to_string()), sid),
+                        _ => continue,
+                    };
+                    self.subs.lock().unwrap().push(Subscription {
+                        conn,
+                        sid: sid.to_string(),
+                        subject: subject.to_string(),
+                        queue,
+                        tx: tx.clone(),
+                    });
+                }
+                "UNSUB" => {
+                    if let Some(sid) = args.first() {
+                        self.subs.lock().unwrap().retain(|s| !(s.conn == conn && s.sid == *sid));
+                    }
+                }
+                "PUB" => {
+                    let (subject, reply, size) = match args[..] {
+                        [subject, size] => (subject, None, size),
+                        [subject, reply, size] => (subject, Some(reply), size),
+                        _ => break,
+                    };
+                    let Some(data) = read_payload(&mut reader, size).await else {
+                        break;
+                    };
+                    self.route(subject, reply, None, &data);
+                }
+                "HPUB" => {
+                    let (subject, reply, header_size, size) = match args[..] {
+                        [subject, header_size, size] => (subject, None, header_size, size),
+                        [subject, reply, header_size, size] => (subject, Some(reply), header_size, size),
+                        _ => break,
+                    };
+                    let Some(data) = read_payload(&mut reader, size).await else {
+                        break;
+                    };
+                    self.route(subject, reply, header_size.parse().ok(), &data);
+                }
+                _ => {}
+            }
+        }
+
+        self.subs.lock().unwrap().retain(|s| s.conn != conn);
+    }
+
+    fn route(&self, subject: &str, reply: Option<&str>, header_size: Option<usize>, data: &[u8]) {
+        let subs = self.subs.lock().unwrap();
+        let matching: Vec<&Subscription> = subs.iter().filter(|s| subject_matches(&s.subject, subject)).collect();
+
+        if matching.is_empty() {
+            drop(subs);
+            if let Some(reply) = reply {
+                self.route(reply, None, Some(NO_RESPONDERS.len()), NO_RESPONDERS);
+            }
+            return;
+        }
+
+        let mut receivers = Vec::new();
+        let mut groups: HashMap<&str, Vec<&Subscription>> = HashMap::new();
+        for sub in matching {
+            match &sub.queue {
+                Some(queue) => groups.entry(queue.as_str()).or_default().push(sub),
+                None => receivers.push(sub),
+            }
+        }
+        for members in groups.into_values() {
+            let pick = self.next_pick.fetch_add(1, Ordering::Relaxed) % members.len();
+            receivers.push(members[pick]);
+        }
+
+        let reply = reply.map(|r| format!(" {r}")).unwrap_or_default();
+        for sub in receivers {
+            let mut frame = match header_size {
+                Some(header_size) => format!("HMSG {subject} {}{reply} {header_size} {}\r\n", sub.sid, data.len()),
+                None => format!("MSG {subject} {}{reply} {}\r\n", sub.sid, data.len()),
+            }
+            .into_bytes();
+            frame.extend_from_slice(data);
+            frame.extend_from_slice(b"\r\n");
+            let _ = sub.tx.send(frame);
+        }
+    }
+}
+
+async fn read_payload<R: AsyncReadExt + Unpin>(reader: &mut R, size: &str) -> Option<Vec<u8>> {
+    let size: usize = size.parse().ok()?;
+    let mut data = vec![0; size + 2];
+    reader.read_exact(&mut data).await.ok()?;
+    data.truncate(size);
+    Some(data)
+}
+
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut pattern = pattern.split('.');
+    let mut subject = subject.split('.');
+
+    loop {
+        match (pattern.next(), subject.next()) {
+            (Some(">"), Some(_)) => return true,
+            (Some("*"), Some(_)) => {}
+            (Some(p), Some(s)) if p == s => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
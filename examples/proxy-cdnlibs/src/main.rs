@@ -1,10 +1,11 @@
 use flate2::read::GzDecoder;
-use http::{Request, Response, StatusCode};
-use prtl_proxy::messages::{HashComponents, ProxyDescriptor};
+use http::{Request, Response};
+use prtl_proxy::messages::{HashComponents, ProxyDescriptor, ProxyError, ProxyErrorKind, RoutePattern};
 use prtl_proxy::utils::json::{FieldFilter, filter_top_level_fields};
-use prtl_proxy::{BoxError, PrtlService};
+use prtl_proxy::{BoxError, Deadline, PrtlService};
 use std::io::Read;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tracing::{info, warn};
 
@@ -15,11 +16,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let service = Arc::new(S::default());
 
     info!("Starting proxy-cdnlibs service");
-    prtl_proxy::serve(service).await?;
+    prtl_proxy::serve_with_shutdown(service, shutdown_signal()).await?;
 
     Ok(())
 }
 
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 #[derive(Debug, Clone)]
 struct RateLimiter {
     limit: Option<u64>,
@@ -67,23 +90,17 @@ impl S {
         }
     }
 
-    async fn validate_request(&self, req: &Request<Vec<u8>>) -> bool {
-        if let Some(path) = req.uri().path_and_query() {
-            return path.as_str().starts_with("/api/anime/");
-        }
-
-        false
-    }
-
     async fn execute_request(&self, request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, BoxError> {
         {
             let limiter = self.rate_limiter.read().await;
             if limiter.should_throttle() {
-                warn!("Rate limit exceeded, returning 429");
-                return Ok(Response::builder()
-                    .status(StatusCode::TOO_MANY_REQUESTS)
-                    .header("Retry-After", "60")
-                    .body(b"Rate limit exceeded".to_vec())?);
+                warn!("Rate limit exceeded, rejecting request");
+                return Err(ProxyError {
+                    kind: ProxyErrorKind::RateLimited,
+                    message: "Rate limit exceeded".into(),
+                    retry_after: Some(Duration::from_secs(60)),
+                }
+                .into());
             }
         }
 
@@ -100,7 +117,14 @@ impl S {
 
         req_builder = req_builder.body(request.body().clone());
 
-        let response = req_builder.send().await?;
+        if let Some(deadline) = request.extensions().get::<Deadline>() {
+            req_builder = req_builder.timeout(deadline.remaining());
+        }
+
+        let response = req_builder
+            .send()
+            .await
+            .map_err(|e| ProxyError::new(ProxyErrorKind::UpstreamFailure, e.to_string()))?;
 
         let rate_limit = response
             .headers()
@@ -227,16 +251,16 @@ impl PrtlService for S {
             service_name: "cdnlibs".into(),
             base_domains: vec!["api.cdnlibs.org".into()],
             hash_settings: HashComponents::URL | HashComponents::QUERY,
-            cache_ttl: Some(std::time::Duration::from_secs(3600)), // 1 hour
+            cache_ttl: Some(Duration::from_secs(3600)), // 1 hour
+            routes: vec![RoutePattern {
+                path: "/api/anime/".into(),
+                methods: vec![],
+            }],
+            ..Default::default()
         }
     }
 
     async fn handle_request(&self, request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, BoxError> {
-        if !self.validate_request(&request).await {
-            let resp = http::Response::builder().status(403).body(Vec::new()).unwrap();
-            return Ok(resp);
-        }
-
         self.execute_request(request).await
     }
 }
@@ -2,31 +2,161 @@ use bitflags::bitflags;
 use http::{Request, Response};
 use serde::{Deserialize, Serialize};
 
+/// Version of the bus protocol spoken by this crate. The gateway rejects registrations from proxies that
+/// advertise a different version.
+pub const PROTOCOL_VERSION: u16 = 2;
+
 bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
     pub struct HashComponents: u8 {
         const URL = 0b0001;
         const QUERY = 0b0010;
         const HEADERS = 0b0100;
+        const METHOD = 0b1000;
+        /// The upstream host and port, so the domains of a proxy don't share entries.
+        const HOST = 0b1_0000;
+        /// The request body, canonicalized according to `ProxyDescriptor::body_canonicalization`. Together with
+        /// `METHOD` this makes responses to POST requests cacheable.
+        const BODY = 0b10_0000;
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How request bodies are normalized before they are hashed into the cache key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BodyCanonicalization {
+    /// The body bytes as sent.
+    #[default]
+    Raw,
+    /// JSON bodies with object keys sorted and whitespace removed. Bodies that aren't JSON are hashed as sent.
+    Json,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ProxyDescriptor {
     pub service_name: String,
+    /// Domains served by the proxy. `example.com` matches the domain and all of its subdomains,
+    /// `*.example.com` only its subdomains. The most specific match wins.
     pub base_domains: Vec<String>,
     pub hash_settings: HashComponents,
+    /// Request headers that are always part of the cache key, on top of those named by a response's `Vary`.
+    /// `HashComponents::HEADERS` hashes every header instead.
+    #[serde(default)]
+    pub key_headers: Vec<String>,
+    #[serde(default)]
+    pub body_canonicalization: BodyCanonicalization,
+    /// Empty means `Rest`.
+    #[serde(default)]
+    pub capabilities: Vec<ParserCapabilities>,
+    /// SHA-256 hashes (hex) of the GraphQL documents a `GraphQl` proxy accepts. `None` accepts any operation.
+    #[serde(default)]
+    pub persisted_queries: Option<Vec<String>>,
+    /// Upper bound for how long responses are cached; `None` disables caching. Within it the gateway follows the
+    /// upstream's `Cache-Control` and `Expires` headers unless `ignore_cache_headers` is set.
     pub cache_ttl: Option<std::time::Duration>,
+    /// Responses with larger bodies, in bytes, are passed through without being cached.
+    #[serde(default)]
+    pub max_cached_body_size: Option<usize>,
+    /// Cache every storable response for `cache_ttl`, whatever the upstream's caching headers say.
+    #[serde(default)]
+    pub ignore_cache_headers: bool,
+    /// How long after `cache_ttl` an entry may still be served while it is refreshed in the background.
+    #[serde(default)]
+    pub stale_while_revalidate: Option<std::time::Duration>,
+    /// How long after `cache_ttl` an entry may still be served when the proxy fails or times out.
+    #[serde(default)]
+    pub stale_if_error: Option<std::time::Duration>,
+    /// How the gateway builds upstream URLs for this proxy.
+    #[serde(default)]
+    pub upstream: UpstreamPolicy,
+    /// Upstream ports the proxy accepts. Empty means any port.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// Requests the proxy accepts. Empty means any method and path.
+    #[serde(default)]
+    pub routes: Vec<RoutePattern>,
+    /// How long the gateway waits for a reply. Falls back to the gateway default and is capped by its maximum.
+    #[serde(default)]
+    pub request_timeout: Option<std::time::Duration>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UpstreamScheme {
+    Http,
+    #[default]
+    Https,
+}
+
+impl UpstreamScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamScheme::Http => "http",
+            UpstreamScheme::Https => "https",
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            UpstreamScheme::Http => 80,
+            UpstreamScheme::Https => 443,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamPolicy {
+    pub scheme: UpstreamScheme,
+    /// Port used when the public path doesn't name one. Defaults to the scheme's port.
+    pub port: Option<u16>,
+    /// Whether clients may choose the port with a `host:port` path segment, e.g. `/example.com:8080/path`.
+    pub allow_explicit_port: bool,
+    /// Origin such as `http://127.0.0.1:8080` that receives every request instead of the requested host,
+    /// keeping path and query. Useful for local stand-ins of the real upstream.
+    pub origin: Option<String>,
+}
+
+impl UpstreamPolicy {
+    /// The port an upstream request ends up on.
+    pub fn effective_port(&self, explicit_port: Option<u16>) -> u16 {
+        explicit_port
+            .or(self.port)
+            .unwrap_or_else(|| self.scheme.default_port())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutePattern {
+    /// Either a path prefix such as `/api`, which matches `/api` and `/api/...` but not `/apiary`, or a glob such
+    /// as `/api/*/items/**` where `*` matches within a single path segment and `**` across segments.
+    pub path: String,
+    /// Allowed methods. Empty means any method.
+    #[serde(default, with = "http_serde_ext::method::vec")]
+    pub methods: Vec<http::Method>,
+}
+
+/// What kind of API a proxy serves. The gateway parses GraphQL requests to key and cache them per operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ParserCapabilities {
     Rest,
     GraphQl,
 }
 
+/// Identity of a single running proxy process. A service may have any number of instances.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyInstance {
+    pub instance_id: String,
+    pub host: String,
+    pub started_at: std::time::SystemTime,
+    /// Number of requests the instance is willing to handle concurrently, used to weight load balancing.
+    pub max_concurrency: Option<u32>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RegisterProxyRequest {
+    #[serde(default)]
+    pub protocol_version: u16,
+    pub instance: ProxyInstance,
+    /// How often the proxy publishes [`BusMessage::Heartbeat`]; the gateway evicts it after several missed beats.
+    pub heartbeat_interval: std::time::Duration,
     pub descriptor: ProxyDescriptor,
 }
 
@@ -36,25 +166,185 @@ pub struct RegisterProxyReply {
     pub reason: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyHeartbeat {
+    pub service_name: String,
+    pub instance_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeregisterProxy {
+    pub service_name: String,
+    pub instance_id: String,
+}
+
+/// Cache entries a proxy wants dropped, always limited to its own service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InvalidationTarget {
+    /// The entry for a `GET` of this upstream URL. Only matches entries whose cache key doesn't include headers.
+    Url(String),
+    /// Every entry whose response carried this tag in its `Surrogate-Key` header.
+    Tag(String),
+    All,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvalidateCache {
+    pub service_name: String,
+    pub target: InvalidationTarget,
+}
+
+/// Sent between gateways when cache entries change, so the others drop their in-process copies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEviction {
+    /// Id of the gateway that changed the entries. It ignores its own evictions.
+    pub gateway_id: String,
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProxyErrorKind {
+    /// The request can't be served as sent, e.g. invalid parameters.
+    BadRequest,
+    /// The upstream could not be reached or answered with garbage.
+    UpstreamFailure,
+    /// The upstream (or the proxy itself) is rate limiting; see [`ProxyError::retry_after`].
+    RateLimited,
+    Internal,
+    /// The proxy received a bus message it can't decode or doesn't handle.
+    UnsupportedMessage,
+    /// The request's deadline passed before the proxy could answer.
+    DeadlineExceeded,
+}
+
+/// Failure reported by a proxy instead of an HTTP response. The gateway maps it to a status code and never
+/// caches it.
+///
+/// Implements [`std::error::Error`], so `PrtlService` implementations can return it from `handle_request` to
+/// control how the failure is reported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyError {
+    pub kind: ProxyErrorKind,
+    pub message: String,
+    pub retry_after: Option<std::time::Duration>,
+}
+
+impl ProxyError {
+    pub fn new(kind: ProxyErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BusMessage {
     RegisterParser(RegisterProxyRequest),
     RegisterParserReply(RegisterProxyReply),
-    ProxyRequest(#[serde(with = "http_serde_ext::request")] Request<Vec<u8>>),
+    ProxyRequest {
+        #[serde(with = "http_serde_ext::request")]
+        request: Request<Vec<u8>>,
+        /// Time left before the gateway stops waiting for the reply.
+        timeout: Option<std::time::Duration>,
+    },
     ProxyResponse(#[serde(with = "http_serde_ext::response")] Response<Vec<u8>>),
+    ProxyError(ProxyError),
     Discovery,
+    Heartbeat(ProxyHeartbeat),
+    Deregister(DeregisterProxy),
+    Invalidate(InvalidateCache),
+    CacheEviction(CacheEviction),
 }
 
-impl BusMessage {
-    pub fn subject_for_register(service: &str) -> String {
-        format!("prtl.proxy.{service}.register")
+pub const DEFAULT_SUBJECT_PREFIX: &str = "prtl";
+
+/// Builds every NATS subject used between the gateway and proxies. All subjects live below a configurable
+/// prefix so that several prtl deployments can share one NATS cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subjects {
+    prefix: String,
+}
+
+impl Default for Subjects {
+    fn default() -> Self {
+        Self::new(DEFAULT_SUBJECT_PREFIX)
+    }
+}
+
+impl Subjects {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self { prefix: prefix.into() }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn register(&self, service: &str) -> String {
+        format!("{}.proxy.{service}.register", self.prefix)
+    }
+
+    /// Matches registrations of every service.
+    pub fn register_all(&self) -> String {
+        self.register("*")
+    }
+
+    pub fn rpc(&self, service: &str) -> String {
+        format!("{}.proxy.{service}.rpc", self.prefix)
+    }
+
+    pub fn instance_rpc(&self, service: &str, instance_id: &str) -> String {
+        format!("{}.proxy.{service}.{instance_id}.rpc", self.prefix)
+    }
+
+    pub fn heartbeat(&self, service: &str) -> String {
+        format!("{}.proxy.{service}.heartbeat", self.prefix)
+    }
+
+    /// Matches heartbeats of every service.
+    pub fn heartbeat_all(&self) -> String {
+        self.heartbeat("*")
+    }
+
+    pub fn deregister(&self, service: &str) -> String {
+        format!("{}.proxy.{service}.deregister", self.prefix)
+    }
+
+    /// Matches deregistrations of every service.
+    pub fn deregister_all(&self) -> String {
+        self.deregister("*")
+    }
+
+    pub fn invalidate(&self, service: &str) -> String {
+        format!("{}.proxy.{service}.invalidate", self.prefix)
+    }
+
+    /// Matches invalidation requests of every service.
+    pub fn invalidate_all(&self) -> String {
+        self.invalidate("*")
+    }
+
+    /// Cache evictions broadcast between gateways.
+    pub fn cache_evictions(&self) -> String {
+        format!("{}.gateway.cache.evict", self.prefix)
     }
 
-    pub fn subject_for_rpc(service: &str) -> String {
-        format!("prtl.proxy.{service}.rpc")
+    pub fn discovery(&self) -> String {
+        format!("{}.discovery", self.prefix)
     }
 
-    pub fn subject_for_discovery() -> String {
-        "prtl.discovery".to_string()
+    /// Discovery requests for a single instance, e.g. one the gateway no longer knows.
+    pub fn instance_discovery(&self, service: &str, instance_id: &str) -> String {
+        format!("{}.proxy.{service}.{instance_id}.discovery", self.prefix)
     }
 }
@@ -0,0 +1,45 @@
+use crate::Error;
+use prtl_messages::{BusMessage, InvalidateCache, InvalidationTarget};
+
+/// Asks the gateway to drop cached responses of this service, e.g. when the proxy learns that upstream data
+/// changed. Available to handlers as a request extension: `request.extensions().get::<Invalidator>()`; it can
+/// be cloned and kept around.
+#[derive(Debug, Clone)]
+pub struct Invalidator {
+    nc: async_nats::Client,
+    subject: String,
+    service_name: String,
+}
+
+impl Invalidator {
+    pub(crate) fn new(nc: async_nats::Client, subject: String, service_name: String) -> Self {
+        Self {
+            nc,
+            subject,
+            service_name,
+        }
+    }
+
+    /// Drops the entry for a `GET` of `url`, as sent to this proxy.
+    pub async fn url(&self, url: impl Into<String>) -> Result<(), Error> {
+        self.send(InvalidationTarget::Url(url.into())).await
+    }
+
+    /// Drops every entry whose response listed `tag` in its `Surrogate-Key` header.
+    pub async fn tag(&self, tag: impl Into<String>) -> Result<(), Error> {
+        self.send(InvalidationTarget::Tag(tag.into())).await
+    }
+
+    pub async fn all(&self) -> Result<(), Error> {
+        self.send(InvalidationTarget::All).await
+    }
+
+    async fn send(&self, target: InvalidationTarget) -> Result<(), Error> {
+        let payload = rmp_serde::to_vec_named(&BusMessage::Invalidate(InvalidateCache {
+            service_name: self.service_name.clone(),
+            target,
+        }))?;
+        self.nc.publish(self.subject.clone(), payload.into()).await?;
+        Ok(())
+    }
+}
@@ -1,21 +1,40 @@
 use http::{Request, Response};
 use prtl_messages::ProxyDescriptor;
+use std::time::{Duration, Instant};
 
+mod invalidate;
 mod serve;
 pub mod utils;
 
+pub use invalidate::Invalidator;
 pub use prtl_messages as messages;
-pub use serve::serve;
+pub use serve::{ServeConfig, serve, serve_with_config, serve_with_shutdown};
 
 #[derive(Debug)]
 pub enum Error {
     Http(http::Error),
+    Connect(async_nats::ConnectError),
+    Subscribe(async_nats::SubscribeError),
+    Encode(rmp_serde::encode::Error),
+    Publish(async_nats::PublishError),
+    /// The gateway refused the proxy's registration, e.g. because its descriptor conflicts with another proxy.
+    RegistrationRejected(String),
+    /// No gateway answered the registration in time.
+    RegistrationTimedOut(Duration),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::Connect(e) => write!(f, "NATS connection error: {}", e),
+            Error::Subscribe(e) => write!(f, "NATS subscription error: {}", e),
+            Error::Encode(e) => write!(f, "Message encoding error: {}", e),
+            Error::Publish(e) => write!(f, "NATS publish error: {}", e),
+            Error::RegistrationRejected(reason) => write!(f, "Registration rejected: {}", reason),
+            Error::RegistrationTimedOut(timeout) => {
+                write!(f, "No gateway answered the registration within {:?}", timeout)
+            }
         }
     }
 }
@@ -28,11 +47,67 @@ impl From<http::Error> for Error {
     }
 }
 
+impl From<async_nats::ConnectError> for Error {
+    fn from(err: async_nats::ConnectError) -> Self {
+        Error::Connect(err)
+    }
+}
+
+impl From<async_nats::SubscribeError> for Error {
+    fn from(err: async_nats::SubscribeError) -> Self {
+        Error::Subscribe(err)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        Error::Encode(err)
+    }
+}
+
+impl From<async_nats::PublishError> for Error {
+    fn from(err: async_nats::PublishError) -> Self {
+        Error::Publish(err)
+    }
+}
+
 pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
+/// Point in time after which the gateway no longer waits for the response. Inserted into the extensions of
+/// requests that carry a timeout, so handlers can bound their upstream calls:
+/// `request.extensions().get::<Deadline>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.0
+    }
+
+    /// Time left until the deadline, zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+}
+
 #[async_trait::async_trait]
 pub trait PrtlService: Send + Sync + 'static {
     fn descriptor(&self) -> ProxyDescriptor;
 
+    /// NATS queue group joined on the service's RPC subject, so that each request is handled by exactly one
+    /// replica. Defaults to the service name; return `None` for broadcast-style services where every replica
+    /// should see every request.
+    fn queue_group(&self) -> Option<String> {
+        Some(self.descriptor().service_name)
+    }
+
     async fn handle_request(&self, request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, BoxError>;
 }
@@ -1,104 +1,366 @@
-use crate::PrtlService;
+use crate::{BoxError, Deadline, Error, Invalidator, PrtlService};
 use futures_util::stream::StreamExt;
-use prtl_messages::{BusMessage, RegisterProxyRequest};
+use prtl_messages::{
+    BusMessage, DeregisterProxy, PROTOCOL_VERSION, ProxyError, ProxyErrorKind, ProxyHeartbeat, ProxyInstance,
+    RegisterProxyRequest, Subjects,
+};
+use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-pub async fn serve(service: Arc<dyn PrtlService>) -> Result<(), Box<dyn std::error::Error>> {
-    let nats_addr = std::env::var("NATS_ADDR").unwrap_or_else(|_| "nats://localhost:4222".into());
-    let nc = async_nats::connect(&nats_addr).await?;
+const REGISTER_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const REGISTER_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long registration keeps retrying while no gateway answers.
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Where and how a proxy instance connects to the gateway.
+#[derive(Debug, Clone)]
+pub struct ServeConfig {
+    pub nats_addr: String,
+    pub subjects: Subjects,
+    pub heartbeat_interval: Duration,
+    /// Requests this instance is willing to handle at once, advertised to the gateway.
+    pub max_concurrency: Option<u32>,
+    /// Host name advertised to the gateway, for logs.
+    pub host: String,
+}
+
+impl Default for ServeConfig {
+    fn default() -> Self {
+        Self {
+            nats_addr: "nats://localhost:4222".into(),
+            subjects: Subjects::default(),
+            heartbeat_interval: Duration::from_secs(5),
+            max_concurrency: None,
+            host: "unknown".into(),
+        }
+    }
+}
+
+impl ServeConfig {
+    /// Reads `NATS_ADDR`, `PRTL_SUBJECT_PREFIX`, `HEARTBEAT_INTERVAL_SECS`, `MAX_CONCURRENCY` and `HOSTNAME`,
+    /// keeping the defaults for missing or invalid ones.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            nats_addr: var("NATS_ADDR").unwrap_or(defaults.nats_addr),
+            subjects: var::<String>("PRTL_SUBJECT_PREFIX")
+                .map(Subjects::new)
+                .unwrap_or(defaults.subjects),
+            heartbeat_interval: var("HEARTBEAT_INTERVAL_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.heartbeat_interval),
+            max_concurrency: var("MAX_CONCURRENCY"),
+            host: var("HOSTNAME").unwrap_or(defaults.host),
+        }
+    }
+}
+
+fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
+/// Serves `service` with the [`ServeConfig`] read from the environment until the process exits.
+pub async fn serve(service: Arc<dyn PrtlService>) -> Result<(), Error> {
+    serve_with_shutdown(service, std::future::pending()).await
+}
+
+/// Like [`serve`], but stops once `signal` resolves: new RPCs are no longer accepted, the gateway is told to
+/// deregister this instance and in-flight requests are allowed to finish before returning.
+pub async fn serve_with_shutdown<F>(service: Arc<dyn PrtlService>, signal: F) -> Result<(), Error>
+where
+    F: Future<Output = ()> + Send,
+{
+    serve_with_config(service, ServeConfig::from_env(), signal).await
+}
+
+/// Like [`serve_with_shutdown`], with an explicit [`ServeConfig`] instead of the environment.
+pub async fn serve_with_config<F>(service: Arc<dyn PrtlService>, config: ServeConfig, signal: F) -> Result<(), Error>
+where
+    F: Future<Output = ()> + Send,
+{
+    let nc = async_nats::connect(&config.nats_addr).await?;
+    let subjects = config.subjects;
+    let heartbeat_interval = config.heartbeat_interval;
 
     let descriptor = service.descriptor();
+    let instance = ProxyInstance {
+        instance_id: uuid::Uuid::new_v4().to_string(),
+        host: config.host,
+        started_at: SystemTime::now(),
+        max_concurrency: config.max_concurrency,
+    };
+    let instance_id = instance.instance_id.clone();
+
+    let register_subject = subjects.register(&descriptor.service_name);
+    let subject = subjects.rpc(&descriptor.service_name);
+    let instance_subject = subjects.instance_rpc(&descriptor.service_name, &instance_id);
+
+    // Subscribe before registering so the gateway never routes to an instance that isn't listening yet
+    let mut subscription = match service.queue_group() {
+        Some(group) => {
+            tracing::info!("Listening on NATS subject: {} (queue group {})", subject, group);
+            nc.queue_subscribe(subject, group).await?
+        }
+        None => {
+            tracing::info!("Listening on NATS subject: {}", subject);
+            nc.subscribe(subject).await?
+        }
+    };
+    tracing::info!("Listening on NATS subject: {}", instance_subject);
+    let mut instance_subscription = nc.subscribe(instance_subject).await?;
+    let deregister_subject = subjects.deregister(&descriptor.service_name);
+    let deregister_payload = rmp_serde::to_vec_named(&BusMessage::Deregister(DeregisterProxy {
+        service_name: descriptor.service_name.clone(),
+        instance_id: instance_id.clone(),
+    }))?;
 
-    let register_subject = BusMessage::subject_for_register(&descriptor.service_name);
-    let subject = BusMessage::subject_for_rpc(&descriptor.service_name);
+    tokio::pin!(signal);
 
     // Register on startup
-    tracing::info!("Registering proxy with NATS");
+    tracing::info!("Registering proxy with NATS as instance {}", instance_id);
     let register_payload = rmp_serde::to_vec_named(&BusMessage::RegisterParser(RegisterProxyRequest {
+        protocol_version: PROTOCOL_VERSION,
+        instance,
+        heartbeat_interval,
         descriptor: descriptor.clone(),
     }))?;
-    nc.publish(register_subject.clone(), register_payload.clone().into())
-        .await?;
+    tokio::select! {
+        result = register(&nc, &register_subject, &register_payload) => result?,
+        _ = &mut signal => {
+            // The gateway may have accepted a request that is still on its way
+            tracing::info!("Shutdown signal received while registering");
+            deregister(&nc, deregister_subject, deregister_payload).await;
+            return Ok(());
+        }
+    }
+
+    // Keep the gateway informed that this instance is alive
+    let heartbeat_subject = subjects.heartbeat(&descriptor.service_name);
+    let heartbeat_payload = rmp_serde::to_vec_named(&BusMessage::Heartbeat(ProxyHeartbeat {
+        service_name: descriptor.service_name.clone(),
+        instance_id: instance_id.clone(),
+    }))?;
+    let nc_heartbeat = nc.clone();
+    let heartbeat_task = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(heartbeat_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = nc_heartbeat
+                .publish(heartbeat_subject.clone(), heartbeat_payload.clone().into())
+                .await
+            {
+                tracing::warn!("Failed to publish heartbeat: {}", e);
+            }
+        }
+    });
 
     // Listen for discovery requests
     let nc_discovery = nc.clone();
     let register_payload_clone = register_payload.clone();
     let register_subject_clone = register_subject.clone();
-    tokio::spawn(async move {
-        let discovery_subject = BusMessage::subject_for_discovery();
-        let mut discovery_sub = match nc_discovery.subscribe(discovery_subject.clone()).await {
-            Ok(s) => s,
+    let discovery_subject = subjects.discovery();
+    let instance_discovery_subject = subjects.instance_discovery(&descriptor.service_name, &instance_id);
+    let discovery_task = tokio::spawn(async move {
+        let subscriptions = futures_util::future::try_join(
+            nc_discovery.subscribe(discovery_subject.clone()),
+            nc_discovery.subscribe(instance_discovery_subject.clone()),
+        );
+        let mut discovery_sub = match subscriptions.await {
+            Ok((all, instance)) => futures_util::stream::select(all, instance),
             Err(e) => {
                 tracing::error!("Failed to subscribe to discovery: {}", e);
                 return;
             }
         };
 
-        tracing::info!("Listening for discovery requests on {}", discovery_subject);
+        tracing::info!(
+            "Listening for discovery requests on {} and {}",
+            discovery_subject,
+            instance_discovery_subject
+        );
 
         while let Some(_msg) = discovery_sub.next().await {
             tracing::info!("Received discovery request, re-registering proxy");
-            if let Err(e) = nc_discovery
-                .publish(register_subject_clone.clone(), register_payload_clone.clone().into())
-                .await
-            {
+            if let Err(e) = register(&nc_discovery, &register_subject_clone, &register_payload_clone).await {
                 tracing::error!("Failed to re-register proxy: {}", e);
             }
         }
     });
 
-    tracing::info!("Listening on NATS subject: {}", subject);
+    let invalidator = Invalidator::new(
+        nc.clone(),
+        subjects.invalidate(&descriptor.service_name),
+        descriptor.service_name.clone(),
+    );
+    let mut in_flight = tokio::task::JoinSet::new();
+
+    loop {
+        tokio::select! {
+            _ = &mut signal => {
+                tracing::info!("Shutdown signal received");
+                break;
+            }
+            msg = subscription.next() => match msg {
+                Some(msg) => {
+                    in_flight.spawn(handle_rpc(service.clone(), nc.clone(), invalidator.clone(), msg));
+                }
+                None => break,
+            },
+            msg = instance_subscription.next() => match msg {
+                Some(msg) => {
+                    in_flight.spawn(handle_rpc(service.clone(), nc.clone(), invalidator.clone(), msg));
+                }
+                None => break,
+            },
+            Some(_) = in_flight.join_next(), if !in_flight.is_empty() => {}
+        }
+    }
+
+    // Stop taking new work and make sure a re-registration can't sneak in after deregistering
+    for sub in [&mut subscription, &mut instance_subscription] {
+        if let Err(e) = sub.unsubscribe().await {
+            tracing::warn!("Failed to unsubscribe from RPC subject: {}", e);
+        }
+    }
+    heartbeat_task.abort();
+    discovery_task.abort();
 
-    let mut subscription = nc.subscribe(subject).await?;
+    deregister(&nc, deregister_subject, deregister_payload).await;
 
-    while let Some(msg) = subscription.next().await {
-        let service = service.clone();
-        let nc = nc.clone();
+    if !in_flight.is_empty() {
+        tracing::info!("Waiting for {} in-flight requests to finish", in_flight.len());
+    }
+    while in_flight.join_next().await.is_some() {}
 
-        tokio::spawn(async move {
-            let reply_subject = match msg.reply {
-                Some(s) => s,
-                None => return,
-            };
+    if let Err(e) = nc.flush().await {
+        tracing::warn!("Failed to flush NATS connection: {}", e);
+    }
 
-            let bus_msg: BusMessage = match rmp_serde::from_slice(&msg.payload) {
-                Ok(r) => r,
-                Err(e) => {
-                    eprintln!("Failed to deserialize request: {}", e);
-                    return;
-                }
-            };
-
-            let response = match bus_msg {
-                BusMessage::ProxyRequest(request) => match service.handle_request(request).await {
-                    Ok(resp) => BusMessage::ProxyResponse(resp),
-                    Err(e) => {
-                        let resp = http::Response::builder()
-                            .status(500)
-                            .body(e.to_string().into_bytes())
-                            .unwrap();
-                        BusMessage::ProxyResponse(resp)
-                    }
-                },
-                _ => {
-                    eprintln!("Unexpected message type");
-                    return;
-                }
-            };
+    tracing::info!("Proxy stopped");
+
+    Ok(())
+}
+
+async fn deregister(nc: &async_nats::Client, subject: String, payload: Vec<u8>) {
+    tracing::info!("Deregistering proxy");
+    if let Err(e) = nc.publish(subject, payload.into()).await {
+        tracing::warn!("Failed to publish deregistration: {}", e);
+    }
+    if let Err(e) = nc.flush().await {
+        tracing::warn!("Failed to flush NATS connection: {}", e);
+    }
+}
+
+async fn handle_rpc(
+    service: Arc<dyn PrtlService>,
+    nc: async_nats::Client,
+    invalidator: Invalidator,
+    msg: async_nats::Message,
+) {
+    let reply_subject = match msg.reply {
+        Some(s) => s,
+        None => return,
+    };
+
+    let response = match rmp_serde::from_slice::<BusMessage>(&msg.payload) {
+        Ok(BusMessage::ProxyRequest { mut request, timeout }) => {
+            request.extensions_mut().insert(invalidator);
+            match handle_request(&*service, request, timeout).await {
+                Ok(resp) => BusMessage::ProxyResponse(resp),
+                Err(e) => BusMessage::ProxyError(e),
+            }
+        }
+        Ok(_) => BusMessage::ProxyError(ProxyError::new(
+            ProxyErrorKind::UnsupportedMessage,
+            "Unexpected message type",
+        )),
+        Err(e) => BusMessage::ProxyError(ProxyError::new(
+            ProxyErrorKind::UnsupportedMessage,
+            format!("Failed to deserialize request: {}", e),
+        )),
+    };
 
-            let payload = match rmp_serde::to_vec(&response) {
+    let payload = match rmp_serde::to_vec(&response) {
+        Ok(p) => p,
+        Err(e) => {
+            let error = ProxyError::new(ProxyErrorKind::Internal, format!("Failed to serialize response: {}", e));
+            match rmp_serde::to_vec(&BusMessage::ProxyError(error)) {
                 Ok(p) => p,
                 Err(e) => {
-                    eprintln!("Failed to serialize response: {}", e);
+                    tracing::error!("Failed to serialize error reply: {}", e);
                     return;
                 }
-            };
-
-            if let Err(e) = nc.publish(reply_subject, payload.into()).await {
-                eprintln!("Failed to send reply: {}", e);
             }
-        });
+        }
+    };
+
+    if let Err(e) = nc.publish(reply_subject, payload.into()).await {
+        tracing::error!("Failed to send reply: {}", e);
+    }
+}
+
+/// Runs the handler with the request's [`Deadline`] attached, giving up once it passes since the gateway
+/// no longer waits for the reply.
+async fn handle_request(
+    service: &dyn PrtlService,
+    mut request: http::Request<Vec<u8>>,
+    timeout: Option<Duration>,
+) -> Result<http::Response<Vec<u8>>, ProxyError> {
+    let Some(timeout) = timeout else {
+        return service.handle_request(request).await.map_err(into_proxy_error);
+    };
+
+    request.extensions_mut().insert(Deadline::after(timeout));
+    match tokio::time::timeout(timeout, service.handle_request(request)).await {
+        Ok(result) => result.map_err(into_proxy_error),
+        Err(_) => Err(ProxyError::new(
+            ProxyErrorKind::DeadlineExceeded,
+            format!("No response within {:?}", timeout),
+        )),
     }
+}
 
-    Ok(())
+/// Keeps errors returned as [`ProxyError`] intact and reports anything else as an internal failure.
+fn into_proxy_error(error: BoxError) -> ProxyError {
+    match error.downcast::<ProxyError>() {
+        Ok(e) => *e,
+        Err(e) => ProxyError::new(ProxyErrorKind::Internal, e.to_string()),
+    }
+}
+
+/// Sends the registration request to the gateway and waits for its verdict, retrying with exponential backoff
+/// while no gateway answers, for up to [`REGISTER_TIMEOUT`].
+async fn register(nc: &async_nats::Client, subject: &str, payload: &[u8]) -> Result<(), Error> {
+    let deadline = tokio::time::Instant::now() + REGISTER_TIMEOUT;
+    let mut backoff = REGISTER_INITIAL_BACKOFF;
+
+    loop {
+        match nc.request(subject.to_string(), payload.to_vec().into()).await {
+            Ok(msg) => match rmp_serde::from_slice::<BusMessage>(&msg.payload) {
+                Ok(BusMessage::RegisterParserReply(reply)) if reply.accepted => {
+                    tracing::info!("Registration accepted by gateway");
+                    return Ok(());
+                }
+                Ok(BusMessage::RegisterParserReply(reply)) => {
+                    let reason = reply.reason.unwrap_or_else(|| "no reason given".into());
+                    tracing::error!("Registration rejected by gateway: {}", reason);
+                    return Err(Error::RegistrationRejected(reason));
+                }
+                Ok(_) => tracing::warn!("Unexpected reply to registration request"),
+                Err(e) => tracing::warn!("Failed to deserialize registration reply: {}", e),
+            },
+            Err(e) => tracing::warn!("Registration request failed: {}", e),
+        }
+
+        if tokio::time::Instant::now() + backoff > deadline {
+            tracing::error!("No gateway answered the registration within {:?}", REGISTER_TIMEOUT);
+            return Err(Error::RegistrationTimedOut(REGISTER_TIMEOUT));
+        }
+        tracing::info!("Retrying registration in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(REGISTER_MAX_BACKOFF);
+    }
 }